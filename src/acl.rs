@@ -0,0 +1,124 @@
+use crate::error::{Error, Result};
+use crate::protocol::Request;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+    // implies read and write, and allows admin commands
+    Admin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Grant {
+    // an empty prefix covers every key
+    pub prefix: String,
+    pub rights: Vec<Access>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Principal {
+    pub name: String,
+    pub token: String,
+    pub grants: Vec<Grant>,
+}
+
+// the acl file is a json document:
+// {"principals": [{"name": "app", "token": "..", "grants": [{"prefix": "app/", "rights": ["read", "write"]}]}]}
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Acl {
+    pub principals: Vec<Principal>,
+}
+
+impl Acl {
+    pub fn load(path: &Path) -> Result<Acl> {
+        let acl: Acl = serde_json::from_slice(&fs::read(path)?)?;
+        for (i, principal) in acl.principals.iter().enumerate() {
+            if principal.token.is_empty() {
+                return Err(Error::InvalidArgErr(format!("{} has an empty token", principal.name)));
+            }
+            if acl.principals[..i].iter().any(|p| p.token == principal.token) {
+                return Err(Error::InvalidArgErr(format!("{} reuses a token", principal.name)));
+            }
+        }
+        Ok(acl)
+    }
+    // find the principal a connection authenticates as
+    pub fn authenticate(&self, token: &str) -> Result<&Principal> {
+        self.principals
+            .iter()
+            .find(|principal| constant_time_eq(principal.token.as_bytes(), token.as_bytes()))
+            .ok_or_else(|| Error::AuthErr("invalid token".to_owned()))
+    }
+}
+
+impl Principal {
+    pub fn check(&self, access: Access, key: &str) -> Result<()> {
+        let allowed = self.grants.iter().any(|grant| {
+            key.starts_with(&grant.prefix)
+                && grant
+                    .rights
+                    .iter()
+                    .any(|&right| right == access || right == Access::Admin)
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::PermissionDeniedErr(format!(
+                "{} may not {:?} {}",
+                self.name, access, key
+            )))
+        }
+    }
+    // the check the server runs before dispatching `request`
+    pub fn authorize(&self, request: &Request) -> Result<()> {
+        match request {
+            Request::Auth { .. } => Ok(()),
+            Request::Get { key } => self.check(Access::Read, key),
+            Request::Set { key, .. } | Request::Remove { key } => self.check(Access::Write, key),
+            Request::Shutdown | Request::Info | Request::SlowLog { .. } => {
+                self.check(Access::Admin, "")
+            }
+        }
+    }
+}
+
+// compare tokens without leaking the length of the common prefix through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[test]
+fn test_check() {
+    let principal = Principal {
+        name: "app".to_owned(),
+        token: "secret".to_owned(),
+        grants: vec![
+            Grant {
+                prefix: "app/".to_owned(),
+                rights: vec![Access::Read, Access::Write],
+            },
+            Grant {
+                prefix: "shared/".to_owned(),
+                rights: vec![Access::Read],
+            },
+        ],
+    };
+    assert!(principal.check(Access::Write, "app/1").is_ok());
+    assert!(principal.check(Access::Read, "shared/1").is_ok());
+    assert!(matches!(
+        principal.check(Access::Write, "shared/1"),
+        Err(Error::PermissionDeniedErr(_))
+    ));
+    assert!(principal.check(Access::Read, "other").is_err());
+    assert!(principal.check(Access::Admin, "app/1").is_err());
+
+    let acl = Acl {
+        principals: vec![principal],
+    };
+    assert_eq!(acl.authenticate("secret").unwrap().name, "app");
+    assert!(matches!(acl.authenticate("secre"), Err(Error::AuthErr(_))));
+}
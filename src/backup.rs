@@ -0,0 +1,223 @@
+use crate::error::{Error, Result};
+use crate::record::Record;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+const MANIFEST_NAME: &str = "MANIFEST";
+const INCREMENT_PREFIX: &str = "incr-";
+// kept in the store directory: the seq covered by its latest backup
+const BACKUP_SEQ_NAME: &str = "backup.seq";
+
+// how far `KvStore::restore_until` replays a backup chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint {
+    Latest,
+    Seq(u64),
+    // milliseconds since the unix epoch
+    Timestamp(u64),
+}
+
+impl RestorePoint {
+    pub(crate) fn includes(&self, record: &Record) -> bool {
+        match *self {
+            RestorePoint::Latest => true,
+            RestorePoint::Seq(seq) => record.seq <= seq,
+            RestorePoint::Timestamp(timestamp) => record.timestamp <= timestamp,
+        }
+    }
+    pub(crate) fn precedes(&self, manifest: &Manifest) -> bool {
+        match *self {
+            RestorePoint::Latest => false,
+            RestorePoint::Seq(seq) => seq < manifest.seq,
+            RestorePoint::Timestamp(timestamp) => timestamp < manifest.timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileDigest {
+    pub name: String,
+    pub len: u64,
+    pub checksum: u32,
+}
+
+// the manifest is written last, so a directory without one is an unfinished backup
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    pub files: Vec<FileDigest>,
+    // highest record seq included
+    pub seq: u64,
+    pub timestamp: u64,
+    // set on incremental backups: the seq of the backup they continue from
+    pub base_seq: Option<u64>,
+}
+
+// copy the first `len` bytes of `src` into `dir/name` through a temp file,
+// fsync it and rename it into place
+pub fn copy_file(src: File, dir: &Path, name: &str, len: u64) -> Result<FileDigest> {
+    let mut reader = src.take(len);
+    let tmp_path = dir.join(format!("{}.tmp", name));
+    let mut tmp = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&tmp_path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        tmp.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+    if copied != len {
+        return Err(Error::IoErr(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("the source of {} is shorter than {} bytes", name, len),
+        )));
+    }
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, dir.join(name))?;
+    Ok(FileDigest {
+        name: name.to_owned(),
+        len,
+        checksum: hasher.finalize(),
+    })
+}
+
+pub fn write_file(dir: &Path, name: &str, bytes: &[u8]) -> Result<FileDigest> {
+    let tmp_path = dir.join(format!("{}.tmp", name));
+    let mut tmp = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&tmp_path)?;
+    tmp.write_all(bytes)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, dir.join(name))?;
+    Ok(FileDigest {
+        name: name.to_owned(),
+        len: bytes.len() as u64,
+        checksum: crc32fast::hash(bytes),
+    })
+}
+
+pub fn remove_manifest(dir: &Path) -> Result<()> {
+    match fs::remove_file(dir.join(MANIFEST_NAME)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::IoErr(e)),
+        _ => Ok(()),
+    }
+}
+
+pub fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    write_file(dir, MANIFEST_NAME, &serde_json::to_vec(manifest)?)?;
+    sync_dir(dir)
+}
+
+pub fn has_manifest(dir: &Path) -> bool {
+    dir.join(MANIFEST_NAME).exists()
+}
+
+pub fn read_manifest(dir: &Path) -> Result<Manifest> {
+    match fs::read(dir.join(MANIFEST_NAME)) {
+        Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::BackupCorruptedErr(
+            format!("{:?} has no {}", dir, MANIFEST_NAME),
+        )),
+        Err(e) => Err(Error::IoErr(e)),
+    }
+}
+
+// check every file listed in the manifest against its recorded length and checksum
+pub fn verify(dir: &Path, manifest: &Manifest) -> Result<()> {
+    for digest in &manifest.files {
+        let buf = fs::read(dir.join(&digest.name))?;
+        if buf.len() as u64 != digest.len || crc32fast::hash(&buf) != digest.checksum {
+            return Err(Error::BackupCorruptedErr(format!(
+                "{} does not match the manifest",
+                digest.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+// the full backup in `dir` followed by its complete increments, oldest first
+pub fn read_chain(dir: &Path) -> Result<Vec<(PathBuf, Manifest)>> {
+    let mut chain = vec![(dir.to_path_buf(), read_manifest(dir)?)];
+    for n in 1.. {
+        let incr_dir = increment_dir(dir, n);
+        if !incr_dir.join(MANIFEST_NAME).exists() {
+            break;
+        }
+        let manifest = read_manifest(&incr_dir)?;
+        let prev_seq = chain.last().map(|(_, m)| m.seq);
+        if manifest.base_seq != prev_seq {
+            return Err(Error::BackupCorruptedErr(format!(
+                "{:?} does not continue from seq {:?}",
+                incr_dir, prev_seq
+            )));
+        }
+        chain.push((incr_dir, manifest));
+    }
+    Ok(chain)
+}
+
+pub fn increment_dir(dir: &Path, n: usize) -> PathBuf {
+    dir.join(format!("{}{:06}", INCREMENT_PREFIX, n))
+}
+
+pub fn read_backup_seq(store_dir: &Path) -> Result<Option<u64>> {
+    match fs::read_to_string(store_dir.join(BACKUP_SEQ_NAME)) {
+        Ok(buf) => Ok(buf.trim().parse().ok()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::IoErr(e)),
+    }
+}
+
+pub fn write_backup_seq(store_dir: &Path, seq: u64) -> Result<()> {
+    write_file(store_dir, BACKUP_SEQ_NAME, seq.to_string().as_bytes())?;
+    Ok(())
+}
+
+pub fn remove_backup_seq(store_dir: &Path) -> Result<()> {
+    match fs::remove_file(store_dir.join(BACKUP_SEQ_NAME)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::IoErr(e)),
+        _ => Ok(()),
+    }
+}
+
+// copy every file of a verified backup into `path`
+pub fn restore_files(backup_dir: &Path, manifest: &Manifest, path: &Path) -> Result<()> {
+    fs::create_dir_all(path)?;
+    for digest in &manifest.files {
+        let src = File::open(backup_dir.join(&digest.name))?;
+        let copied = copy_file(src, path, &digest.name, digest.len)?;
+        if copied.checksum != digest.checksum {
+            return Err(Error::BackupCorruptedErr(format!(
+                "{} changed while restoring",
+                digest.name
+            )));
+        }
+    }
+    sync_dir(path)
+}
+
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,230 @@
+use crate::engines::KvsEngine;
+use crate::error::{Error, Result};
+use crate::KvStore;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::BufRead,
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchCommand {
+    Get { key: String },
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl FromStr for BatchCommand {
+    type Err = Error;
+
+    // `get <key>`, `set <key> <value>` where the value runs to the end of the line,
+    // or `rm <key>`
+    fn from_str(line: &str) -> Result<BatchCommand> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim_start();
+        let (key, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        match (name, key.is_empty(), value.is_empty()) {
+            ("get", false, true) => Ok(BatchCommand::Get { key: key.to_owned() }),
+            ("set", false, false) => Ok(BatchCommand::Set {
+                key: key.to_owned(),
+                value: value.trim_start().to_owned(),
+            }),
+            ("rm", false, true) => Ok(BatchCommand::Remove { key: key.to_owned() }),
+            _ => Err(Error::InvalidArgErr(format!("invalid command {:?}", line))),
+        }
+    }
+}
+
+impl BatchCommand {
+    // run the command on its own, as `kvs get`, `set` and `rm` and the lines of a
+    // non-transactional batch do
+    pub fn apply<E: KvsEngine>(self, engine: &E) -> Result<Outcome> {
+        match self {
+            BatchCommand::Get { key } => engine.get(key).map(Outcome::Value),
+            BatchCommand::Set { key, value } => engine.set(key, value).map(|()| Outcome::Done),
+            BatchCommand::Remove { key } => engine.remove(key).map(|()| Outcome::Done),
+        }
+    }
+}
+
+impl fmt::Display for BatchCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BatchCommand::Get { key } => write!(f, "get {}", key),
+            BatchCommand::Set { key, .. } => write!(f, "set {}", key),
+            BatchCommand::Remove { key } => write!(f, "rm {}", key),
+        }
+    }
+}
+
+// read one command per line, blank lines and lines starting with `#` are skipped.
+// Each command comes with its line number.
+pub fn parse_batch<R: BufRead>(reader: R) -> Result<Vec<(usize, BatchCommand)>> {
+    let mut commands = vec![];
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let command = trimmed.parse().map_err(|e| match e {
+            Error::InvalidArgErr(msg) => Error::InvalidArgErr(format!("line {}: {}", i + 1, msg)),
+            e => e,
+        })?;
+        commands.push((i + 1, command));
+    }
+    Ok(commands)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchMode {
+    // stop at the first failing command, the ones before it stay applied
+    StopOnError,
+    // run every command and report each failure
+    ContinueOnError,
+    // apply every write or none of them
+    Transactional,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Done,
+    // the value read by a get
+    Value(Option<String>),
+    Failed(String),
+    // not run, because an earlier command failed
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub results: Vec<(usize, BatchCommand, Outcome)>,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub skipped: u64,
+    // why a transactional batch wrote nothing
+    pub aborted: Option<String>,
+}
+
+impl fmt::Display for BatchSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (line, command, outcome) in &self.results {
+            match outcome {
+                Outcome::Done => writeln!(f, "{}: {}: ok", line, command)?,
+                Outcome::Value(Some(value)) => writeln!(f, "{}: {}: {}", line, command, value)?,
+                Outcome::Value(None) => writeln!(f, "{}: {}: Key not found", line, command)?,
+                Outcome::Failed(e) => writeln!(f, "{}: {}: failed: {}", line, command, e)?,
+                Outcome::Skipped => writeln!(f, "{}: {}: skipped", line, command)?,
+            }
+        }
+        if let Some(reason) = &self.aborted {
+            writeln!(f, "transaction aborted, nothing was written: {}", reason)?;
+        }
+        writeln!(
+            f,
+            "{} succeeded, {} failed, {} skipped",
+            self.succeeded, self.failed, self.skipped
+        )
+    }
+}
+
+impl BatchSummary {
+    fn new(
+        commands: Vec<(usize, BatchCommand)>,
+        outcomes: Vec<Outcome>,
+        aborted: Option<String>,
+    ) -> BatchSummary {
+        let mut summary = BatchSummary {
+            results: vec![],
+            succeeded: 0,
+            failed: 0,
+            skipped: 0,
+            aborted,
+        };
+        for ((line, command), outcome) in commands.into_iter().zip(outcomes) {
+            match outcome {
+                Outcome::Failed(_) => summary.failed += 1,
+                Outcome::Skipped => summary.skipped += 1,
+                _ => summary.succeeded += 1,
+            }
+            summary.results.push((line, command, outcome));
+        }
+        summary
+    }
+    pub fn is_success(&self) -> bool {
+        self.failed == 0 && self.skipped == 0 && self.aborted.is_none()
+    }
+}
+
+impl KvStore {
+    pub fn apply_batch(
+        &self,
+        commands: Vec<(usize, BatchCommand)>,
+        mode: BatchMode,
+    ) -> Result<BatchSummary> {
+        let (outcomes, aborted) = match mode {
+            BatchMode::Transactional => self.apply_transaction(&commands)?,
+            _ => {
+                let mut outcomes = Vec::with_capacity(commands.len());
+                for (_, command) in &commands {
+                    if mode == BatchMode::StopOnError
+                        && outcomes.iter().any(|o| matches!(o, Outcome::Failed(_)))
+                    {
+                        outcomes.push(Outcome::Skipped);
+                        continue;
+                    }
+                    let result = command.clone().apply(self);
+                    outcomes.push(result.unwrap_or_else(|e| Outcome::Failed(format!("{:?}", e))));
+                }
+                (outcomes, None)
+            }
+        };
+        Ok(BatchSummary::new(commands, outcomes, aborted))
+    }
+    // dry run the batch on top of the store so a remove of a missing key can be named
+    // and gets see the batch's own writes, then hand the writes to `write_batch`
+    fn apply_transaction(
+        &self,
+        commands: &[(usize, BatchCommand)],
+    ) -> Result<(Vec<Outcome>, Option<String>)> {
+        let mut pending: HashMap<&str, Option<&str>> = HashMap::new();
+        let mut outcomes = Vec::with_capacity(commands.len());
+        for (_, command) in commands {
+            let outcome = match command {
+                BatchCommand::Get { key } => Outcome::Value(match pending.get(key.as_str()) {
+                    Some(value) => value.map(str::to_owned),
+                    None => self.get(key.clone())?,
+                }),
+                BatchCommand::Set { key, value } => {
+                    pending.insert(key, Some(value));
+                    Outcome::Done
+                }
+                BatchCommand::Remove { key } => {
+                    let exists = match pending.get(key.as_str()) {
+                        Some(value) => value.is_some(),
+                        None => self.contains_key(key),
+                    };
+                    if !exists {
+                        // nothing of the batch happened, so the values its gets saw
+                        // through the pending writes were never there either
+                        let reason = format!("{:?}", Error::KeyNotExistErr);
+                        let mut aborted = vec![Outcome::Skipped; commands.len()];
+                        aborted[outcomes.len()] = Outcome::Failed(reason.clone());
+                        return Ok((aborted, Some(reason)));
+                    }
+                    pending.insert(key, None);
+                    Outcome::Done
+                }
+            };
+            outcomes.push(outcome);
+        }
+        let writes: Vec<BatchCommand> = commands.iter().map(|(_, command)| command.clone()).collect();
+        // a quota or a concurrent writer can still turn the batch down
+        match self.write_batch(&writes) {
+            Ok(()) => Ok((outcomes, None)),
+            Err(e) => Ok((vec![Outcome::Skipped; commands.len()], Some(format!("{:?}", e)))),
+        }
+    }
+}
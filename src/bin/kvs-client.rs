@@ -0,0 +1,74 @@
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+use kvs::{Error, KvsClient, Result};
+
+fn main() -> Result<()> {
+  let mut args:Vec<String> = env::args().collect();
+  args.remove(0);
+  if args.is_empty() {
+    panic!();
+  }
+  // options may come anywhere, what is left is the command and its arguments.
+  // --token overrides KVS_TOKEN.
+  let mut addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+  let mut token = env::var("KVS_TOKEN").ok();
+  let mut command = Vec::new();
+  let mut i = 0;
+  while i < args.len() {
+    match args[i].as_str() {
+      "-V" => {
+        println!("{:?}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+      },
+      "--addr" => {
+        if i + 1 >= args.len() {
+          panic!();
+        }
+        addr = args[i + 1].parse().expect("expected IP:PORT");
+        i += 1;
+      },
+      "--token" => {
+        if i + 1 >= args.len() {
+          panic!();
+        }
+        token = Some(args[i + 1].clone());
+        i += 1;
+      },
+      arg if arg.starts_with('-') => panic!(),
+      arg => command.push(arg),
+    }
+    i += 1;
+  }
+
+  let mut client = KvsClient::connect(addr)?;
+  if let Some(token) = token {
+    client.authenticate(token)?;
+  }
+  match command.as_slice() {
+    ["get", key] => match client.get(key.to_string())? {
+      Some(value) => println!("{}", value),
+      None => println!("Key not found"),
+    },
+    ["set", key, value] => client.set(key.to_string(), value.to_string())?,
+    ["shutdown"] => client.shutdown()?,
+    ["info"] => print!("{}", client.info()?),
+    // the latest slow requests, ten unless given a count
+    ["slowlog"] | ["slowlog", _] => {
+      let count = command.get(1).map_or(10, |count| count.parse().expect("expected a number"));
+      for entry in client.slowlog(count)? {
+        println!("{} {} {}us {} {:?} {}", entry.id, entry.timestamp, entry.duration_us, entry.command, entry.key, entry.client);
+      }
+    },
+    ["rm", key] => match client.remove(key.to_string()) {
+      Ok(()) => {},
+      Err(Error::KeyNotExistErr) => {
+        eprintln!("Key not found");
+        process::exit(1);
+      },
+      Err(e) => return Err(e),
+    },
+    _ => panic!(),
+  }
+  Ok(())
+}
@@ -0,0 +1,158 @@
+use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use kvs::{Acl, Engine, Error, Expiring, KvsServer, LogConfig, Metrics, SlowLog, NaiveThreadPool, RayonThreadPool, Result, ServerConfig, SharedQueueThreadPool, ThreadPool};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use tracing::{info, Level};
+
+fn main() -> Result<()> {
+  let mut args:Vec<String> = env::args().collect();
+  args.remove(0);
+  // the server reports what it serves, so it logs from info up unless KVS_LOG says otherwise
+  let mut log_config = LogConfig::from_env()?;
+  if env::var_os("KVS_LOG").is_none() {
+    log_config.level = Level::INFO;
+  }
+  let mut config = ServerConfig::default();
+  // --data-dir overrides KVS_DATA_DIR, the data directory defaults to the current one
+  let mut data_dir = match env::var_os("KVS_DATA_DIR") {
+    Some(dir) => PathBuf::from(dir),
+    None => env::current_dir()?,
+  };
+  let mut engine = None;
+  let mut pool = String::from("shared");
+  let mut threads = thread::available_parallelism().map_or(4, |n| n.get() as u32);
+  let mut queue_size = None;
+  let mut quotas = Vec::new();
+  // like redis, requests above 10ms are kept, the latest 128 of them
+  let mut slowlog_threshold = Duration::from_micros(10_000);
+  let mut slowlog_capacity = 128;
+  let mut slowlog_file = None;
+  let mut i = 0;
+  while i < args.len() {
+    match args[i].as_str() {
+      "-V" => {
+        println!("{:?}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+      },
+      option => {
+        if i + 1 >= args.len() {
+          panic!();
+        }
+        let value = &args[i + 1];
+        match option {
+          "--engine" => match value.as_str() {
+            "kvs" | "sled" => engine = Some(value.clone()),
+            _ => panic!(),
+          },
+          "--data-dir" => data_dir = PathBuf::from(value),
+          "--addr" => config.addr = value.parse::<SocketAddr>().expect("expected IP:PORT"),
+          // naive starts a thread per connection, shared and rayon keep `--threads` workers
+          "--thread-pool" => match value.as_str() {
+            "naive" | "shared" | "rayon" => pool = value.clone(),
+            _ => panic!(),
+          },
+          // a json file of principals, their tokens and their grants, see `kvs::Acl`
+          "--acl" => config.acl = Some(Acl::load(Path::new(value))?),
+          "--http-addr" => config.http_addr = Some(value.parse::<SocketAddr>().expect("expected IP:PORT")),
+          "--protocol" => config.protocol = value.parse()?,
+          "--shutdown-timeout" => config.shutdown_timeout = Duration::from_secs(value.parse().expect("expected seconds")),
+          // these override KVS_LOG, KVS_LOG_FORMAT and KVS_LOG_FILE
+          "--log-level" => log_config.level = kvs::parse_level(value)?,
+          "--log-format" => log_config.format = value.parse()?,
+          "--log-file" => log_config.file = Some(PathBuf::from(value)),
+          "--slowlog-threshold-us" => slowlog_threshold = Duration::from_micros(value.parse().expect("expected microseconds")),
+          "--slowlog-capacity" => slowlog_capacity = value.parse().expect("expected a number"),
+          // also append every slow request to this file, as json lines
+          "--slowlog-file" => slowlog_file = Some(PathBuf::from(value)),
+          "--threads" => threads = value.parse().expect("expected a number"),
+          // jobs the shared pool queues before it turns connections away
+          "--queue-size" => queue_size = Some(value.parse().expect("expected a number")),
+          "--max-connections" => config.max_connections = value.parse().expect("expected a number"),
+          "--max-request-size" => config.max_request_size = value.parse().expect("expected bytes"),
+          // timeouts are in seconds, 0 waits forever
+          "--idle-timeout" => config.idle_timeout = timeout(value),
+          "--read-timeout" => config.read_timeout = timeout(value),
+          "--write-timeout" => config.write_timeout = timeout(value),
+          // per client, a client being its principal or else its ip address
+          "--rate-limit-requests" => config.rate_limit_requests = Some(value.parse().expect("expected requests a second")),
+          "--rate-limit-bytes" => config.rate_limit_bytes = Some(value.parse().expect("expected bytes a second")),
+          // PREFIX=BYTES, may be given more than once
+          "--quota" => match value.rsplit_once('=') {
+            Some((prefix, limit)) => quotas.push((prefix.to_owned(), limit.parse::<u64>().expect("expected bytes"))),
+            None => panic!(),
+          },
+          _ => panic!(),
+        }
+        i += 1;
+      },
+    }
+    i += 1;
+  }
+  kvs::init_logging(&log_config)?;
+
+  // a directory keeps the engine it was created with, kvs if it has none yet. What
+  // isn't a directory is turned down by prepare_data_dir.
+  let engine = match engine {
+    Some(engine) => engine,
+    None if data_dir.is_dir() => kvs::read_engine_marker(&data_dir)?.unwrap_or_else(|| String::from("kvs")),
+    None => String::from("kvs"),
+  };
+  info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+  info!("storage engine: {}", engine);
+  info!("data directory: {}", data_dir.display());
+  info!("thread pool: {} with {} threads", pool, threads);
+  kvs::prepare_data_dir(&data_dir, &engine)?;
+  // a write the client saw succeed must survive the server being killed, kvs has
+  // handed it to the os by then but sled still buffers it
+  let store = match kvs::open_engine(&engine, &data_dir)? {
+    Engine::Sled(store) => Engine::Sled(store.sync_writes()),
+    store => store,
+  };
+  for (prefix, limit) in &quotas {
+    match &store {
+      Engine::Kvs(store) => store.set_quota(prefix, *limit),
+      Engine::Sled(_) => return Err(Error::InvalidArgErr(String::from("quotas need the kvs engine"))),
+    }
+    info!("quota: {} bytes under {:?}", limit, prefix);
+  }
+  // the kvs engine also reports its compactions and fsyncs
+  let metrics = Arc::new(Metrics::new());
+  if let Engine::Kvs(store) = &store {
+    store.set_metrics(Arc::clone(&metrics))?;
+  }
+  let store = Expiring::open(store, &data_dir)?;
+  let slowlog = Arc::new(match slowlog_file {
+    Some(path) => SlowLog::with_file(slowlog_threshold, slowlog_capacity, &path)?,
+    None => SlowLog::new(slowlog_threshold, slowlog_capacity),
+  });
+  match pool.as_str() {
+    "naive" => serve(store, NaiveThreadPool::new(threads)?, config, metrics, slowlog),
+    "shared" => match queue_size {
+      Some(capacity) => serve(store, SharedQueueThreadPool::with_capacity(threads, capacity)?, config, metrics, slowlog),
+      None => serve(store, SharedQueueThreadPool::new(threads)?, config, metrics, slowlog),
+    },
+    "rayon" => serve(store, RayonThreadPool::new(threads)?, config, metrics, slowlog),
+    _ => panic!(),
+  }
+}
+
+fn timeout(value: &str) -> Option<Duration> {
+  match value.parse().expect("expected seconds") {
+    0 => None,
+    secs => Some(Duration::from_secs(secs)),
+  }
+}
+
+// serve until SIGTERM, SIGINT or a shutdown request, then drain and flush. The http
+// listener serves the metrics on /metrics.
+fn serve<P: ThreadPool>(store: Expiring<Engine>, pool: P, config: ServerConfig, metrics: Arc<Metrics>, slowlog: Arc<SlowLog>) -> Result<()> {
+  let server = KvsServer::new(store, pool, config).with_metrics(metrics).with_slowlog(slowlog);
+  for &signal in &[SIGTERM, SIGINT] {
+    signal_hook::flag::register(signal, server.shutdown_flag())?;
+  }
+  server.run()
+}
@@ -1,5 +1,9 @@
 use std::env;
-use kvs::Result;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use kvs::{BatchCommand, BatchMode, DataFormat, Error, Outcome, Difference, FsckMode, ImportPolicy, KvStore, Engine, LogConfig, RestorePoint, Result};
 
 fn main() -> Result<()> {
   let mut args:Vec<String> = env::args().collect();
@@ -7,8 +11,25 @@ fn main() -> Result<()> {
   if args.is_empty() {
     panic!();
   }
-  println!("args: {:?}", args);
+  // global options come before the command. They override KVS_DATA_DIR, KVS_LOG,
+  // KVS_LOG_FORMAT and KVS_LOG_FILE, the data directory defaults to the current one.
+  let mut log_config = LogConfig::from_env()?;
+  let mut data_dir = match env::var_os("KVS_DATA_DIR") {
+    Some(dir) => PathBuf::from(dir),
+    None => env::current_dir()?,
+  };
   let mut i = 0;
+  while i + 1 < args.len() && args[i].starts_with("--") {
+    match args[i].as_str() {
+      "--data-dir" => data_dir = PathBuf::from(&args[i + 1]),
+      "--log-level" => log_config.level = kvs::parse_level(&args[i + 1])?,
+      "--log-format" => log_config.format = args[i + 1].parse()?,
+      "--log-file" => log_config.file = Some(PathBuf::from(&args[i + 1])),
+      _ => panic!(),
+    }
+    i += 2;
+  }
+  kvs::init_logging(&log_config)?;
   while i < args.len() {
     match args[i].as_str() {
       "-V" => {
@@ -18,28 +39,214 @@ fn main() -> Result<()> {
         let key_index = i + 1;
         if key_index >= args.len() {
           panic!();
-        } else {
-          let get_key = &args[key_index];
-          panic!("unimplemented");
         }
+        run_command(&data_dir, BatchCommand::Get { key: args[key_index].clone() })?;
+        i = key_index;
       },
       "set" => {
         let key_index = i + 1;
         let val_index = key_index + 1;
         if val_index >= args.len() {
           panic!();
-        } else {
-          panic!("unimplemented");
         }
+        let command = BatchCommand::Set { key: args[key_index].clone(), value: args[val_index].clone() };
+        run_command(&data_dir, command)?;
+        i = val_index;
       },
       "rm" => {
         let key_index = i + 1;
         if key_index >= args.len() {
           panic!();
+        }
+        run_command(&data_dir, BatchCommand::Remove { key: args[key_index].clone() })?;
+        i = key_index;
+      },
+      // `backup DIR` takes a full backup and keeps no history for later increments.
+      // `backup --incremental DIR` starts a chain in an empty DIR with a full backup and
+      // adds an increment on later runs; while a chain runs, compaction keeps every record
+      // newer than its last backup, until a plain `backup` ends the chain.
+      "backup" => {
+        let mut incremental = false;
+        while i + 1 < args.len() && args[i + 1].starts_with("--") {
+          match args[i + 1].as_str() {
+            "--incremental" => incremental = true,
+            _ => panic!(),
+          }
+          i += 1;
+        }
+        let dir_index = i + 1;
+        if dir_index >= args.len() {
+          panic!();
+        }
+        let store = open_store(&data_dir)?;
+        if incremental {
+          store.backup_incremental(Path::new(&args[dir_index]))?;
+        } else {
+          store.backup(Path::new(&args[dir_index]))?;
+        }
+        i = dir_index;
+      },
+      "restore" => {
+        let mut point = RestorePoint::Latest;
+        let mut overwrite = false;
+        while i + 1 < args.len() && args[i + 1].starts_with("--") {
+          if args[i + 1] == "--overwrite" {
+            overwrite = true;
+            i += 1;
+            continue;
+          }
+          let n: u64 = args.get(i + 2).and_then(|n| n.parse().ok()).expect("expected a number");
+          match args[i + 1].as_str() {
+            "--until-seq" => point = RestorePoint::Seq(n),
+            "--until-time" => point = RestorePoint::Timestamp(n),
+            _ => panic!(),
+          }
+          i += 2;
+        }
+        let dir_index = i + 1;
+        if dir_index >= args.len() {
+          panic!();
+        }
+        kvs::prepare_data_dir(&data_dir, "kvs")?;
+        KvStore::restore_until(Path::new(&args[dir_index]), &data_dir, point, overwrite)?;
+        i = dir_index;
+      },
+      // `fsck --repair` cuts off a torn tail and drops damaged records, keeping every
+      // intact record after them. `--truncate-at-damage` cuts the log off at the first
+      // damaged record instead, dropping the intact records after it too.
+      "fsck" => {
+        let mut mode = FsckMode::Check;
+        while i + 1 < args.len() {
+          match args[i + 1].as_str() {
+            "--repair" if mode == FsckMode::Check => mode = FsckMode::Repair,
+            "--repair" => {},
+            "--truncate-at-damage" => mode = FsckMode::Truncate,
+            _ => panic!(),
+          }
+          i += 1;
+        }
+        let report = kvs::fsck(&data_dir, mode)?;
+        print!("{}", report);
+        if !report.is_clean() && !report.repaired {
+          process::exit(1);
+        }
+      },
+      // convert a store written by a kvs without a log header, see `kvs::upgrade`
+      "upgrade" => {
+        match kvs::upgrade(&data_dir)? {
+          Some(keys) => println!("upgraded {} keys", keys),
+          None => println!("already up to date"),
+        }
+      },
+      "export" => {
+        let mut format = DataFormat::JsonLines;
+        let mut prefix = String::new();
+        while i + 2 < args.len() && args[i + 1].starts_with("--") {
+          match args[i + 1].as_str() {
+            "--format" => format = args[i + 2].parse()?,
+            "--prefix" => prefix = args[i + 2].clone(),
+            _ => panic!(),
+          }
+          i += 2;
+        }
+        let store = open_store(&data_dir)?;
+        if i + 1 < args.len() {
+          i += 1;
+          store.export(BufWriter::new(File::create(&args[i])?), format, &prefix)?;
+        } else {
+          store.export(io::stdout().lock(), format, &prefix)?;
+        }
+      },
+      "import" => {
+        let mut format = DataFormat::JsonLines;
+        let mut policy = ImportPolicy::Overwrite;
+        while i + 2 < args.len() && args[i + 1].starts_with("--") {
+          match args[i + 1].as_str() {
+            "--format" => format = args[i + 2].parse()?,
+            "--policy" => policy = args[i + 2].parse()?,
+            _ => panic!(),
+          }
+          i += 2;
+        }
+        let store = open_store(&data_dir)?;
+        let summary = if i + 1 < args.len() {
+          i += 1;
+          store.import(File::open(&args[i])?, format, policy)?
+        } else {
+          store.import(io::stdin().lock(), format, policy)?
+        };
+        println!("imported {}, skipped {}", summary.imported, summary.skipped);
+      },
+      "migrate" => {
+        let mut from = String::from("kvs");
+        let mut to = String::from("sled");
+        while i + 2 < args.len() && args[i + 1].starts_with("--") {
+          match args[i + 1].as_str() {
+            "--from" => from = args[i + 2].clone(),
+            "--to" => to = args[i + 2].clone(),
+            _ => panic!(),
+          }
+          i += 2;
+        }
+        let dst_index = i + 2;
+        if dst_index >= args.len() {
+          panic!();
+        }
+        let summary = kvs::migrate_dir(&from, Path::new(&args[i + 1]), &to, Path::new(&args[dst_index]))?;
+        println!("migrated {} keys, checksum {:08x}", summary.keys, summary.checksum);
+        i = dst_index;
+      },
+      "diff" => {
+        let b_index = i + 2;
+        if b_index >= args.len() {
+          panic!();
+        }
+        let a = open_marked(Path::new(&args[i + 1]))?;
+        let b = open_marked(Path::new(&args[b_index]))?;
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        let summary = kvs::diff(&a, &b, |d| {
+          match d {
+            Difference::Added { key, value } => writeln!(out, "+ {}\t{}", key, value)?,
+            Difference::Removed { key, value } => writeln!(out, "- {}\t{}", key, value)?,
+            Difference::Changed { key, old, new } => writeln!(out, "~ {}\t{}\t{}", key, old, new)?,
+          }
+          Ok(())
+        })?;
+        writeln!(out, "{} added, {} removed, {} changed", summary.added, summary.removed, summary.changed)?;
+        if !summary.is_empty() {
+          process::exit(1);
+        }
+        i = b_index;
+      },
+      "batch" => {
+        let mut mode = BatchMode::StopOnError;
+        while i + 1 < args.len() && args[i + 1].starts_with("--") {
+          match args[i + 1].as_str() {
+            "--transactional" => mode = BatchMode::Transactional,
+            "--continue-on-error" => mode = BatchMode::ContinueOnError,
+            _ => panic!(),
+          }
+          i += 1;
+        }
+        let commands = if i + 1 < args.len() {
+          i += 1;
+          kvs::parse_batch(BufReader::new(File::open(&args[i])?))?
         } else {
-          panic!("unimplemented");
+          kvs::parse_batch(io::stdin().lock())?
+        };
+        let store = open_store(&data_dir)?;
+        let summary = store.apply_batch(commands, mode)?;
+        print!("{}", summary);
+        if !summary.is_success() {
+          drop(store);
+          process::exit(1);
         }
       },
+      "stats" => {
+        let store = open_store(&data_dir)?;
+        print!("{}", store.stats()?);
+      },
       _ => {
         panic!();
       }
@@ -47,4 +254,34 @@ fn main() -> Result<()> {
     i += 1;
   }
   Ok(())
-}
\ No newline at end of file
+}
+
+// run a single get, set or rm the way a batch runs its lines. A missing key prints
+// "Key not found", which fails the command only for rm.
+fn run_command(data_dir: &Path, command: BatchCommand) -> Result<()> {
+  let store = open_store(data_dir)?;
+  match command.apply(&store) {
+    Ok(Outcome::Value(Some(value))) => println!("{}", value),
+    Ok(Outcome::Value(None)) => println!("Key not found"),
+    Ok(_) => {},
+    Err(Error::KeyNotExistErr) => {
+      println!("Key not found");
+      drop(store);
+      process::exit(1);
+    },
+    Err(e) => return Err(e),
+  }
+  Ok(())
+}
+
+// open the kvs store in `data_dir`, creating and marking the directory if needed
+fn open_store(data_dir: &Path) -> Result<KvStore> {
+  kvs::prepare_data_dir(data_dir, "kvs")?;
+  KvStore::open(data_dir)
+}
+
+// open a data directory with the engine named by its marker, kvs if it has none
+fn open_marked(path: &Path) -> Result<Engine> {
+  let engine = kvs::read_engine_marker(path)?.unwrap_or_else(|| String::from("kvs"));
+  kvs::open_engine(&engine, path)
+}
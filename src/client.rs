@@ -0,0 +1,107 @@
+use crate::error::{Error, Result};
+use crate::protocol::{read_frame, write_frame, Request, Response, CONNECTION_ID};
+use crate::slowlog::SlowEntry;
+use std::{
+    io::{BufReader, BufWriter, Write},
+    net::{TcpStream, ToSocketAddrs},
+    thread,
+};
+
+// a connection to a kvs-server speaking the framed protocol
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    next_id: u64,
+}
+
+impl KvsClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            next_id: 0,
+        })
+    }
+    // present `token` to a server that checks an acl
+    pub fn authenticate(&mut self, token: String) -> Result<()> {
+        self.call(Request::Auth { token }).map(|_| ())
+    }
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.call(Request::Get { key })
+    }
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.call(Request::Set { key, value }).map(|_| ())
+    }
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.call(Request::Remove { key }).map(|_| ())
+    }
+    // stop the server, it answers before it closes its listeners
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.call(Request::Shutdown).map(|_| ())
+    }
+    // the statistics of the server's engine, as text
+    pub fn info(&mut self) -> Result<String> {
+        self.call(Request::Info).map(Option::unwrap_or_default)
+    }
+    // the most recent `count` slow requests the server recorded, newest first
+    pub fn slowlog(&mut self, count: u64) -> Result<Vec<SlowEntry>> {
+        let lines = self.call(Request::SlowLog { count })?.unwrap_or_default();
+        let entries = lines.lines().map(serde_json::from_str).collect::<serde_json::Result<_>>();
+        Ok(entries?)
+    }
+    // send every request before reading any response, then match the responses to
+    // the requests by id. The results come back in the order of `requests`. Requests
+    // are written from a second thread, so neither side stalls on a full socket buffer
+    // while the other waits for it to read.
+    pub fn pipeline(&mut self, requests: Vec<Request>) -> Result<Vec<Result<Option<String>>>> {
+        let first = self.next_id;
+        self.next_id += requests.len() as u64;
+        let (reader, writer) = (&mut self.reader, &mut self.writer);
+        let mut results: Vec<Option<Result<Option<String>>>> =
+            requests.iter().map(|_| None).collect();
+        thread::scope(|scope| {
+            let sender = scope.spawn(|| -> Result<()> {
+                for (id, request) in (first..).zip(&requests) {
+                    write_frame(writer, id, request)?;
+                }
+                writer.flush()?;
+                Ok(())
+            });
+            for _ in 0..requests.len() {
+                let (id, response) = match read_frame::<_, Response>(reader)? {
+                    Some(frame) => frame,
+                    None => return Err(closed()),
+                };
+                if id == CONNECTION_ID {
+                    return Err(Result::from(response).err().unwrap_or_else(closed));
+                }
+                match id.checked_sub(first).and_then(|i| results.get_mut(i as usize)) {
+                    Some(slot) if slot.is_none() => *slot = Some(response.into()),
+                    _ => return Err(Error::ProtocolErr(format!("unexpected response to {}", id))),
+                }
+            }
+            sender.join().unwrap()
+        })?;
+        // every slot is filled, there were as many distinct ids as requests
+        Ok(results.into_iter().flatten().collect())
+    }
+    fn call(&mut self, request: Request) -> Result<Option<String>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        write_frame(&mut self.writer, id, &request)?;
+        self.writer.flush()?;
+        match read_frame::<_, Response>(&mut self.reader)? {
+            Some((got, response)) if got == id || got == CONNECTION_ID => response.into(),
+            Some((got, _)) => Err(Error::ProtocolErr(format!(
+                "expected the response to {}, got {}",
+                id, got
+            ))),
+            None => Err(closed()),
+        }
+    }
+}
+
+fn closed() -> Error {
+    Error::ProtocolErr("the server closed the connection".to_owned())
+}
@@ -0,0 +1,79 @@
+use crate::engines::KvsEngine;
+use crate::error::Result;
+use std::cmp::Ordering;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Difference {
+    // only in the second store
+    Added { key: String, value: String },
+    // only in the first store
+    Removed { key: String, value: String },
+    Changed { key: String, old: String, new: String },
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DiffSummary {
+    pub added: u64,
+    pub removed: u64,
+    pub changed: u64,
+    pub unchanged: u64,
+}
+
+impl DiffSummary {
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.removed == 0 && self.changed == 0
+    }
+}
+
+// walk both stores in key order and hand every difference to `f` as soon as it is found,
+// so only the key lists are held in memory
+pub fn diff<A, B, F>(a: &A, b: &B, mut f: F) -> Result<DiffSummary>
+where
+    A: KvsEngine,
+    B: KvsEngine,
+    F: FnMut(Difference) -> Result<()>,
+{
+    let mut summary = DiffSummary::default();
+    let mut a_keys = a.keys()?.into_iter().peekable();
+    let mut b_keys = b.keys()?.into_iter().peekable();
+    loop {
+        let order = match (a_keys.peek(), b_keys.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(a_key), Some(b_key)) => a_key.cmp(b_key),
+        };
+        let (a_val, b_val, key) = match order {
+            Ordering::Less => {
+                let key = a_keys.next().unwrap();
+                (a.get(key.clone())?, None, key)
+            }
+            Ordering::Greater => {
+                let key = b_keys.next().unwrap();
+                (None, b.get(key.clone())?, key)
+            }
+            Ordering::Equal => {
+                let key = a_keys.next().unwrap();
+                b_keys.next();
+                (a.get(key.clone())?, b.get(key.clone())?, key)
+            }
+        };
+        match (a_val, b_val) {
+            (Some(value), None) => {
+                summary.removed += 1;
+                f(Difference::Removed { key, value })?;
+            }
+            (None, Some(value)) => {
+                summary.added += 1;
+                f(Difference::Added { key, value })?;
+            }
+            (Some(old), Some(new)) if old != new => {
+                summary.changed += 1;
+                f(Difference::Changed { key, old, new })?;
+            }
+            (Some(_), Some(_)) => summary.unchanged += 1,
+            (None, None) => {}
+        }
+    }
+    Ok(summary)
+}
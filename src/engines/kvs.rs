@@ -0,0 +1,28 @@
+use crate::engines::KvsEngine;
+use crate::{KvStore, Result, Stats};
+
+impl KvsEngine for KvStore {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn set(&self, key: String, val: String) -> Result<()> {
+        KvStore::set(self, key, val)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(KvStore::keys(self))
+    }
+
+    fn flush(&self) -> Result<()> {
+        KvStore::flush(self)
+    }
+
+    fn stats(&self) -> Result<Option<Stats>> {
+        KvStore::stats(self).map(Some)
+    }
+}
@@ -0,0 +1,134 @@
+use crate::error::{Error, Result};
+use crate::kv;
+use crate::stats::Stats;
+use crate::KvStore;
+use std::{fs, io, path::Path};
+
+pub use self::sled::SledKvsEngine;
+
+mod kvs;
+mod sled;
+
+// written into a data directory so it is only ever opened with the engine that created it
+const ENGINE_MARKER: &str = "engine";
+
+// clones are handles on the same store, so each thread serving it takes its own
+pub trait KvsEngine: Clone + Send + 'static {
+    fn get(&self, key: String) -> Result<Option<String>>;
+    fn set(&self, key: String, val: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()>;
+    // every live key, sorted
+    fn keys(&self) -> Result<Vec<String>>;
+    // persist everything written so far, called before a clean shutdown
+    fn flush(&self) -> Result<()>;
+    // key count, sizes and compactions, for engines that keep track of them
+    fn stats(&self) -> Result<Option<Stats>> {
+        Ok(None)
+    }
+}
+
+// an engine picked at runtime, e.g. by the marker of a data directory
+#[derive(Clone)]
+pub enum Engine {
+    Kvs(KvStore),
+    Sled(SledKvsEngine),
+}
+
+impl KvsEngine for Engine {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self {
+            Engine::Kvs(engine) => engine.get(key),
+            Engine::Sled(engine) => engine.get(key),
+        }
+    }
+
+    fn set(&self, key: String, val: String) -> Result<()> {
+        match self {
+            Engine::Kvs(engine) => engine.set(key, val),
+            Engine::Sled(engine) => engine.set(key, val),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self {
+            Engine::Kvs(engine) => engine.remove(key),
+            Engine::Sled(engine) => engine.remove(key),
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        match self {
+            Engine::Kvs(engine) => KvsEngine::keys(engine),
+            Engine::Sled(engine) => engine.keys(),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        match self {
+            Engine::Kvs(engine) => engine.flush(),
+            Engine::Sled(engine) => engine.flush(),
+        }
+    }
+
+    fn stats(&self) -> Result<Option<Stats>> {
+        match self {
+            Engine::Kvs(engine) => KvsEngine::stats(engine),
+            Engine::Sled(engine) => engine.stats(),
+        }
+    }
+}
+
+pub fn open_engine(name: &str, path: &Path) -> Result<Engine> {
+    match name {
+        "kvs" => Ok(Engine::Kvs(KvStore::open(path)?)),
+        "sled" => Ok(Engine::Sled(SledKvsEngine::open(path)?)),
+        _ => Err(Error::InvalidArgErr(format!("unknown engine {}", name))),
+    }
+}
+
+// whether `path` has the files of a `name` store, which may hold no keys
+pub(crate) fn holds_store(name: &str, path: &Path) -> bool {
+    match name {
+        "kvs" => path.join(kv::DB_NAME).is_file(),
+        "sled" => path.join("conf").is_file(),
+        _ => false,
+    }
+}
+
+pub fn read_engine_marker(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path.join(ENGINE_MARKER)) {
+        Ok(name) => Ok(Some(name.trim().to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::IoErr(e)),
+    }
+}
+
+pub fn write_engine_marker(path: &Path, name: &str) -> Result<()> {
+    fs::write(path.join(ENGINE_MARKER), name)?;
+    Ok(())
+}
+
+// make `path` ready to hold a store of `engine`. A missing directory is created, then
+// the directory must be writable and, if it is marked, marked with the same engine.
+// Whether another process has the store open is checked when the engine opens it.
+pub fn prepare_data_dir(path: &Path, engine: &str) -> Result<()> {
+    match fs::metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => {
+            return Err(Error::InvalidArgErr(format!("{:?} is not a directory", path)))
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir_all(path)?,
+        Err(e) => return Err(Error::IoErr(e)),
+    }
+    // permission bits don't tell about read-only mounts, so try to write
+    let probe = path.join(".kvs-probe");
+    if let Err(e) = fs::write(&probe, b"") {
+        return Err(Error::InvalidArgErr(format!("{:?} is not writable: {}", path, e)));
+    }
+    fs::remove_file(&probe)?;
+    match read_engine_marker(path)? {
+        Some(marker) if marker != engine => Err(Error::WrongEngineErr(marker)),
+        Some(_) => Ok(()),
+        None => write_engine_marker(path, engine),
+    }
+}
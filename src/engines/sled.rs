@@ -0,0 +1,64 @@
+use crate::engines::KvsEngine;
+use crate::error::{Error, Result};
+use std::path::Path;
+
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: sled::Db,
+    sync: bool,
+}
+
+impl SledKvsEngine {
+    pub fn new(db: sled::Db) -> SledKvsEngine {
+        SledKvsEngine { db, sync: false }
+    }
+    pub fn open(path: &Path) -> Result<SledKvsEngine> {
+        Ok(SledKvsEngine::new(sled::open(path)?))
+    }
+    // flush after every set and remove, so a write that returned survives the process
+    // being killed. Sled otherwise flushes in the background and on `flush`, which is
+    // what bulk writers like migrate want.
+    pub fn sync_writes(mut self) -> SledKvsEngine {
+        self.sync = true;
+        self
+    }
+    fn synced(&self) -> Result<()> {
+        if self.sync {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.db.get(key)? {
+            Some(val) => Ok(Some(String::from_utf8(val.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, key: String, val: String) -> Result<()> {
+        self.db.insert(key, val.into_bytes())?;
+        self.synced()
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.db.remove(key)?.ok_or(Error::KeyNotExistErr)?;
+        self.synced()
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        // sled iterates in byte order, which is the same order as sorted strings
+        self.db
+            .iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
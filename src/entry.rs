@@ -1,35 +1,65 @@
 use crate::{error::Error, Result};
 use serde::{Deserialize, Serialize};
+use std::fs::File;
+#[cfg(test)]
 use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom},
-    path::Path, collections::HashMap,
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{Seek, SeekFrom},
+    path::Path,
 };
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Entry {
+    // which db file the position refers to, compaction starts a new generation.
+    // Only meaningful while the store is open, so it is not persisted.
+    #[serde(skip)]
+    gen: u64,
     position: u64,
     offset: usize,
 }
 impl Entry {
-    pub fn new(position: u64, offset: usize) -> Entry {
-        Entry { position, offset }
+    pub fn new(gen: u64, position: u64, offset: usize) -> Entry {
+        Entry { gen, position, offset }
     }
-    pub fn get_string(file: &mut File, entry: &Entry) -> Result<String> {
-        match file.seek(SeekFrom::Start(entry.position)) {
-            Ok(_) => {
-                let mut buf = vec![0; entry.offset];
-                file.read_exact(&mut buf).unwrap();
-                Ok(String::from_utf8(buf).unwrap())
+    // positional read, so readers sharing `file` never move each other's cursor
+    pub fn get_string(file: &File, entry: &Entry) -> Result<String> {
+        let mut buf = vec![0; entry.offset];
+        read_exact_at(file, &mut buf, entry.position)?;
+        String::from_utf8(buf).map_err(|_| {
+            Error::CorruptedRecordErr(format!("invalid utf-8 at {}", entry.position))
+        })
+    }
+    pub fn gen(&self) -> u64 {
+        self.gen
+    }
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+    pub fn value_len(&self) -> usize {
+        self.offset
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], position: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, position)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut position: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, position)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                position += n as u64;
             }
-            Err(_) => Err(Error::FileSeekErr),
         }
     }
-    pub fn get_entry(file: &File, val: &[u8]) -> Entry {
-        let position = file.metadata().unwrap().len();
-        let offset = val.len();
-        Entry { position, offset }
-    }
+    Ok(())
 }
 
 #[test]
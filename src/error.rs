@@ -5,5 +5,59 @@ pub enum Error {
     OpenFileErr,
     GetErr,
     KeyNotExistErr,
+    KeyExistErr(String),
     FileSeekErr,
+    IoErr(std::io::Error),
+    SerdeErr(serde_json::Error),
+    BackupErr(String),
+    BackupCorruptedErr(String),
+    CorruptedRecordErr(String),
+    // a data file written in a format this version cannot read
+    FormatErr(String),
+    CsvErr(csv::Error),
+    InvalidArgErr(String),
+    SledErr(sled::Error),
+    Utf8Err(std::string::FromUtf8Error),
+    WrongEngineErr(String),
+    MigrateErr(String),
+    ThreadPoolErr(String),
+    // the store is held by another process
+    LockedErr(String),
+    ProtocolErr(String),
+    AuthErr(String),
+    PermissionDeniedErr(String),
+    // the server has no room to queue more work
+    BusyErr,
+    // a quota or rate limit was hit
+    LimitExceededErr(String),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::IoErr(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::SerdeErr(err)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Error {
+        Error::CsvErr(err)
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(err: sled::Error) -> Error {
+        Error::SledErr(err)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(err: std::string::FromUtf8Error) -> Error {
+        Error::Utf8Err(err)
+    }
 }
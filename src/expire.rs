@@ -0,0 +1,138 @@
+use crate::backup;
+use crate::engines::KvsEngine;
+use crate::error::{Error, Result};
+use crate::record;
+use crate::stats::Stats;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// the deadlines, a json map from key to milliseconds since the unix epoch
+pub const TTL_NAME: &str = "ttl.json";
+
+// an engine whose keys can be given a time to live. A key is removed the first time it
+// is touched after its deadline, until then it still takes space in the engine. A set
+// or remove clears the deadline. The deadlines are written to `ttl.json` in the data
+// directory whenever they change, so they survive a restart.
+#[derive(Clone)]
+pub struct Expiring<E: KvsEngine> {
+    engine: E,
+    path: PathBuf,
+    deadlines: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl<E: KvsEngine> Expiring<E> {
+    pub fn open(engine: E, path: &Path) -> Result<Expiring<E>> {
+        let deadlines = match fs::read(path.join(TTL_NAME)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(Error::IoErr(e)),
+        };
+        Ok(Expiring {
+            engine,
+            path: path.to_owned(),
+            deadlines: Arc::new(Mutex::new(deadlines)),
+        })
+    }
+    // remove `key` once `ttl` has passed, false if there is no such key
+    pub fn expire(&self, key: String, ttl: Duration) -> Result<bool> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        if self.purge(&mut deadlines, &key)? || self.engine.get(key.clone())?.is_none() {
+            return Ok(false);
+        }
+        deadlines.insert(key, record::now_millis().saturating_add(ttl.as_millis() as u64));
+        self.persist(&deadlines)?;
+        Ok(true)
+    }
+    // whether `key` is past its deadline, in which case it is removed
+    fn expired(&self, key: &str) -> Result<bool> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        self.purge(&mut deadlines, key)
+    }
+    fn purge(&self, deadlines: &mut BTreeMap<String, u64>, key: &str) -> Result<bool> {
+        match deadlines.get(key) {
+            Some(&deadline) if deadline <= record::now_millis() => {
+                match self.engine.remove(key.to_owned()) {
+                    Ok(()) | Err(Error::KeyNotExistErr) => {}
+                    Err(e) => return Err(e),
+                }
+                deadlines.remove(key);
+                self.persist(deadlines)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+    // run `write`, dropping the deadline of `key` along with it if it has one. Writes to
+    // keys without a deadline don't take the lock, an expire racing them acts as if it
+    // came second.
+    fn clearing<T>(&self, key: &str, write: impl FnOnce() -> Result<T>) -> Result<T> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        if !deadlines.contains_key(key) {
+            drop(deadlines);
+            return write();
+        }
+        let result = write()?;
+        deadlines.remove(key);
+        self.persist(&deadlines)?;
+        Ok(result)
+    }
+    fn persist(&self, deadlines: &BTreeMap<String, u64>) -> Result<()> {
+        backup::write_file(&self.path, TTL_NAME, &serde_json::to_vec(deadlines)?)?;
+        Ok(())
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Expiring<E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if self.expired(&key)? {
+            return Ok(None);
+        }
+        self.engine.get(key)
+    }
+
+    fn set(&self, key: String, val: String) -> Result<()> {
+        self.clearing(&key.clone(), || self.engine.set(key, val))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        if self.expired(&key)? {
+            return Err(Error::KeyNotExistErr);
+        }
+        self.clearing(&key.clone(), || self.engine.remove(key))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let now = record::now_millis();
+        let due: Vec<String> = deadlines
+            .iter()
+            .filter(|(_, &deadline)| deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &due {
+            match self.engine.remove(key.clone()) {
+                Ok(()) | Err(Error::KeyNotExistErr) => {}
+                Err(e) => return Err(e),
+            }
+            deadlines.remove(key);
+        }
+        if !due.is_empty() {
+            self.persist(&deadlines)?;
+        }
+        drop(deadlines);
+        self.engine.keys()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn stats(&self) -> Result<Option<Stats>> {
+        self.engine.stats()
+    }
+}
@@ -0,0 +1,131 @@
+use crate::error::{Error, Result};
+use crate::KvStore;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    // one `{"key": .., "value": ..}` object per line
+    JsonLines,
+    // a `key,value` header followed by one pair per row
+    Csv,
+}
+
+impl FromStr for DataFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DataFormat> {
+        match s {
+            "jsonl" | "json" => Ok(DataFormat::JsonLines),
+            "csv" => Ok(DataFormat::Csv),
+            _ => Err(Error::InvalidArgErr(format!("unknown format {}", s))),
+        }
+    }
+}
+
+// what `import` does with a key that already exists in the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportPolicy {
+    Overwrite,
+    Skip,
+    Fail,
+}
+
+impl FromStr for ImportPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ImportPolicy> {
+        match s {
+            "overwrite" => Ok(ImportPolicy::Overwrite),
+            "skip" => Ok(ImportPolicy::Skip),
+            "fail" => Ok(ImportPolicy::Fail),
+            _ => Err(Error::InvalidArgErr(format!("unknown policy {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub imported: u64,
+    pub skipped: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+impl KvStore {
+    // stream every live pair whose key starts with `prefix`, in key order
+    pub fn export<W: Write>(&self, writer: W, format: DataFormat, prefix: &str) -> Result<u64> {
+        let keys = self.keys().into_iter().filter(|key| key.starts_with(prefix));
+        let mut exported = 0;
+        match format {
+            DataFormat::JsonLines => {
+                let mut writer = writer;
+                for key in keys {
+                    if let Some(value) = self.get(key.clone())? {
+                        serde_json::to_writer(&mut writer, &Pair { key, value })?;
+                        writer.write_all(b"\n")?;
+                        exported += 1;
+                    }
+                }
+                writer.flush()?;
+            }
+            DataFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                for key in keys {
+                    if let Some(value) = self.get(key.clone())? {
+                        writer.serialize(Pair { key, value })?;
+                        exported += 1;
+                    }
+                }
+                writer.flush()?;
+            }
+        }
+        Ok(exported)
+    }
+    // read pairs written by `export` (or any tool producing the same format)
+    pub fn import<R: Read>(
+        &self,
+        reader: R,
+        format: DataFormat,
+        policy: ImportPolicy,
+    ) -> Result<ImportSummary> {
+        let mut summary = ImportSummary::default();
+        match format {
+            DataFormat::JsonLines => {
+                for line in BufReader::new(reader).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let pair: Pair = serde_json::from_str(&line)?;
+                    self.import_pair(pair, policy, &mut summary)?;
+                }
+            }
+            DataFormat::Csv => {
+                for pair in csv::Reader::from_reader(reader).deserialize() {
+                    self.import_pair(pair?, policy, &mut summary)?;
+                }
+            }
+        }
+        Ok(summary)
+    }
+    fn import_pair(&self, pair: Pair, policy: ImportPolicy, summary: &mut ImportSummary) -> Result<()> {
+        if policy != ImportPolicy::Overwrite && self.contains_key(&pair.key) {
+            if policy == ImportPolicy::Fail {
+                return Err(Error::KeyExistErr(pair.key));
+            }
+            summary.skipped += 1;
+            return Ok(());
+        }
+        self.set(pair.key, pair.value)?;
+        summary.imported += 1;
+        Ok(())
+    }
+}
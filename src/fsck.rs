@@ -0,0 +1,222 @@
+use crate::backup;
+use crate::entry::Entry;
+use crate::error::{Error, Result};
+use crate::kv::{self, IndexFile, DB_NAME, INDEX_NAME};
+use crate::record::{self, Damage, Record, RecordIter, RecordKind};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+const REWRITE_NAME: &str = "db.db.fsck";
+
+// what `fsck` may change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckMode {
+    // only report
+    Check,
+    // cut off a torn tail, drop damaged records while keeping every intact one after
+    // them, and rebuild index.db
+    Repair,
+    // like `Repair`, but cut the log off at its first damaged record, dropping the
+    // intact records after it too
+    Truncate,
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub records: u64,
+    pub live_keys: usize,
+    pub db_len: u64,
+    // records inside the log that failed to decode: position, length and why
+    pub damaged: Vec<(u64, u64, String)>,
+    // where a torn record at the end of db.db starts and why it was rejected
+    pub corrupt_tail: Option<(u64, String)>,
+    // index.db is empty or absent, `KvStore::open` rebuilds it from the log
+    pub index_missing: bool,
+    pub index_unreadable: bool,
+    // live in the log but absent from index.db
+    pub orphaned: Vec<String>,
+    // in index.db but not pointing at the live record of its key
+    pub dangling: Vec<String>,
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.damaged.is_empty()
+            && self.corrupt_tail.is_none()
+            && !self.index_unreadable
+            && self.orphaned.is_empty()
+            && self.dangling.is_empty()
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} records, {} live keys, {} bytes",
+            self.records, self.live_keys, self.db_len
+        )?;
+        for (position, len, reason) in &self.damaged {
+            writeln!(f, "damaged record at {} ({} bytes): {}", position, len, reason)?;
+        }
+        if let Some((position, reason)) = &self.corrupt_tail {
+            writeln!(f, "corrupt record at {}: {}", position, reason)?;
+        }
+        if self.index_missing {
+            writeln!(f, "index missing")?;
+        }
+        if self.index_unreadable {
+            writeln!(f, "index unreadable")?;
+        }
+        for key in &self.orphaned {
+            writeln!(f, "orphaned key: {}", key)?;
+        }
+        for key in &self.dangling {
+            writeln!(f, "dangling key: {}", key)?;
+        }
+        if self.repaired {
+            writeln!(f, "repaired")?;
+        }
+        Ok(())
+    }
+}
+
+// what a walk over the log found
+#[derive(Default)]
+struct Scan {
+    records: u64,
+    live: HashMap<String, Entry>,
+    tail: u64,
+    seq: u64,
+    damaged: Vec<(u64, u64, String)>,
+    corrupt_tail: Option<(u64, String)>,
+}
+
+// walk every intact record of `db`, handing each to `visit`. Damaged records are
+// skipped, a torn one at the end stops the walk.
+fn scan(db: &File, mut visit: impl FnMut(&Record) -> Result<()>) -> Result<Scan> {
+    let mut found = Scan::default();
+    let mut reader = BufReader::new(db);
+    reader.seek(SeekFrom::Start(0))?;
+    let mut records = RecordIter::log(reader)?;
+    loop {
+        let start = records.position();
+        let (position, record) = match records.next() {
+            None => break,
+            Some(Ok(item)) => item,
+            Some(Err(Error::CorruptedRecordErr(reason))) => {
+                match Damage::classify(db, start, records.position())? {
+                    Damage::TornTail => {
+                        found.corrupt_tail = Some((start, reason));
+                        break;
+                    }
+                    Damage::Skip { next } => {
+                        found.damaged.push((start, next - start, reason));
+                        let mut reader = BufReader::new(db);
+                        reader.seek(SeekFrom::Start(next))?;
+                        records = RecordIter::resume(reader, next);
+                        continue;
+                    }
+                }
+            }
+            Some(Err(e)) => return Err(e),
+        };
+        visit(&record)?;
+        found.records += 1;
+        found.tail = position;
+        found.seq = found.seq.max(record.seq);
+        for (position, record) in record.flatten(position) {
+            if record.kind == RecordKind::Set {
+                let entry = Entry::new(0, position + record.value_offset(), record.value.len());
+                found.live.insert(record.key, entry);
+            } else {
+                found.live.remove(&record.key);
+            }
+        }
+    }
+    Ok(found)
+}
+
+// check the store in `path`, which must not be open, and repair it as far as `mode` allows
+pub fn fsck(path: &Path, mode: FsckMode) -> Result<FsckReport> {
+    let db_path = path.join(DB_NAME);
+    let _lock = kv::lock_dir(path)?;
+    let db = OpenOptions::new().read(true).write(true).open(&db_path)?;
+    let mut report = FsckReport {
+        db_len: db.metadata()?.len(),
+        ..FsckReport::default()
+    };
+    let mut found = scan(&db, |_| Ok(()))?;
+    report.records = found.records;
+    report.live_keys = found.live.len();
+    report.damaged = found.damaged.clone();
+    report.corrupt_tail = found.corrupt_tail.clone();
+
+    match fs::read_to_string(path.join(INDEX_NAME)) {
+        Ok(buf) if !buf.is_empty() => {
+            match serde_json::from_str::<IndexFile>(&buf) {
+                Ok(IndexFile { entries: index, .. }) => {
+                    for (key, entry) in &index {
+                        if found.live.get(key) != Some(entry) {
+                            report.dangling.push(key.clone());
+                        }
+                    }
+                    for key in found.live.keys() {
+                        if !index.contains_key(key) {
+                            report.orphaned.push(key.clone());
+                        }
+                    }
+                    report.dangling.sort();
+                    report.orphaned.sort();
+                }
+                Err(_) => report.index_unreadable = true,
+            }
+        }
+        Ok(_) => report.index_missing = true,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => report.index_missing = true,
+        Err(e) => return Err(e.into()),
+    }
+
+    if mode == FsckMode::Check || report.is_clean() {
+        return Ok(report);
+    }
+    let db = if let (FsckMode::Truncate, Some((position, _, _))) = (mode, found.damaged.first()) {
+        db.set_len(*position)?;
+        db.sync_all()?;
+        db
+    } else if !found.damaged.is_empty() {
+        // copy the intact records into a new log, a torn tail is left behind with the rest
+        let rewrite_path = path.join(REWRITE_NAME);
+        let mut rewritten = BufWriter::new(File::create(&rewrite_path)?);
+        rewritten.write_all(&record::LOG_MAGIC)?;
+        scan(&db, |record| Ok(rewritten.write_all(&record.encode())?))?;
+        rewritten.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&rewrite_path, &db_path)?;
+        backup::sync_dir(path)?;
+        OpenOptions::new().read(true).write(true).open(&db_path)?
+    } else {
+        if let Some((position, _)) = &found.corrupt_tail {
+            db.set_len(*position)?;
+            db.sync_all()?;
+        }
+        db
+    };
+    if db.metadata()?.len() != report.db_len {
+        found = scan(&db, |_| Ok(()))?;
+    }
+    let index = IndexFile {
+        db_len: db.metadata()?.len(),
+        tail: found.tail,
+        seq: found.seq,
+        entries: found.live.into_iter().collect(),
+    };
+    backup::write_file(path, INDEX_NAME, &serde_json::to_vec(&index)?)?;
+    report.repaired = true;
+    Ok(report)
+}
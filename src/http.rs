@@ -0,0 +1,275 @@
+use crate::acl::{Access, Principal};
+use crate::engines::KvsEngine;
+use crate::error::{Error, Result};
+use crate::metrics::Metrics;
+use serde_json::json;
+use std::io::{BufRead, Read, Write};
+
+const MAX_BODY_LEN: u64 = 64 * 1024 * 1024;
+const PAGE_LIMIT: usize = 100;
+const MAX_PAGE_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    // path and query, still percent-encoded
+    pub target: String,
+    pub body: String,
+    // the bearer token of the authorization header
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpRequest {
+    // read one HTTP/1.1 request, `Ok(None)` means the connection was closed
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Option<HttpRequest>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let mut parts = line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method.to_owned(), target.to_owned()),
+            _ => return Err(Error::ProtocolErr(format!("bad request line {:?}", line))),
+        };
+        let mut content_len = 0;
+        let mut token = None;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(Error::ProtocolErr("truncated headers".to_owned()));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_len = value
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|&len| len <= MAX_BODY_LEN)
+                        .ok_or_else(|| Error::ProtocolErr("bad content-length".to_owned()))?;
+                } else if name.eq_ignore_ascii_case("authorization") {
+                    token = value.trim().strip_prefix("Bearer ").map(str::to_owned);
+                }
+            }
+        }
+        let mut body = Vec::new();
+        if reader.take(content_len).read_to_end(&mut body)? < content_len as usize {
+            return Err(Error::ProtocolErr("truncated body".to_owned()));
+        }
+        Ok(Some(HttpRequest {
+            method,
+            target,
+            body: String::from_utf8(body)?,
+            token,
+        }))
+    }
+}
+
+impl HttpResponse {
+    fn json(status: u16, body: serde_json::Value) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+    // the `/metrics` endpoint, scraped by prometheus
+    pub fn metrics(metrics: &Metrics) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: metrics.render(),
+        }
+    }
+    pub(crate) fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse::json(status, json!({ "error": message }))
+    }
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            429 => "Too Many Requests",
+            503 => "Service Unavailable",
+            507 => "Insufficient Storage",
+            _ => "Internal Server Error",
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// serve the REST api:
+// GET, PUT (the body is the value) and DELETE on /keys/{key}, and
+// GET /keys?after={key}&limit={n} for a page of keys in order
+pub fn route<E: KvsEngine>(engine: &E, request: &HttpRequest) -> HttpResponse {
+    let (path, query) = match request.target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (request.target.as_str(), ""),
+    };
+    let result = if path == "/keys" {
+        match request.method.as_str() {
+            "GET" => list(engine, query),
+            _ => return HttpResponse::error(405, "method not allowed"),
+        }
+    } else if let Some(key) = path.strip_prefix("/keys/") {
+        let key = match percent_decode(key) {
+            Some(key) if !key.is_empty() => key,
+            _ => return HttpResponse::error(400, "invalid key"),
+        };
+        match request.method.as_str() {
+            "GET" => engine.get(key.clone()).map(|value| match value {
+                Some(value) => HttpResponse::json(200, json!({ "key": key, "value": value })),
+                None => HttpResponse::error(404, "key not found"),
+            }),
+            "PUT" => engine
+                .set(key.clone(), request.body.clone())
+                .map(|()| HttpResponse::json(200, json!({ "key": key }))),
+            "DELETE" => engine
+                .remove(key.clone())
+                .map(|()| HttpResponse::json(200, json!({ "key": key }))),
+            _ => return HttpResponse::error(405, "method not allowed"),
+        }
+    } else {
+        return HttpResponse::error(404, "not found");
+    };
+    match result {
+        Ok(response) => response,
+        Err(Error::KeyNotExistErr) => HttpResponse::error(404, "key not found"),
+        Err(Error::InvalidArgErr(msg)) => HttpResponse::error(400, &msg),
+        Err(Error::LimitExceededErr(msg)) => HttpResponse::error(507, &msg),
+        Err(e) => HttpResponse::error(500, &format!("{:?}", e)),
+    }
+}
+
+// the check the server runs before routing `request`, listing keys needs read access
+// to every key and the metrics need admin
+pub fn authorize(principal: &Principal, request: &HttpRequest) -> Result<()> {
+    let path = request.target.split('?').next().unwrap_or("");
+    match path {
+        "/keys" => return principal.check(Access::Read, ""),
+        "/metrics" => return principal.check(Access::Admin, ""),
+        _ => {}
+    }
+    match path.strip_prefix("/keys/").and_then(percent_decode) {
+        Some(key) if request.method == "GET" => principal.check(Access::Read, &key),
+        Some(key) => principal.check(Access::Write, &key),
+        // route turns the request down anyway
+        None => Ok(()),
+    }
+}
+
+// the label of `request` in the server metrics
+pub fn command_name(request: &HttpRequest) -> &'static str {
+    let path = request.target.split('?').next().unwrap_or("");
+    match (request.method.as_str(), path) {
+        ("GET", "/metrics") => "http_metrics",
+        ("GET", "/keys") => "http_list",
+        ("GET", path) if path.starts_with("/keys/") => "http_get",
+        ("PUT", path) if path.starts_with("/keys/") => "http_set",
+        ("DELETE", path) if path.starts_with("/keys/") => "http_rm",
+        _ => "http_unknown",
+    }
+}
+
+// the key of a request on /keys/{key}
+pub(crate) fn request_key(request: &HttpRequest) -> Option<String> {
+    let path = request.target.split('?').next().unwrap_or("");
+    path.strip_prefix("/keys/").and_then(percent_decode)
+}
+
+fn list<E: KvsEngine>(engine: &E, query: &str) -> Result<HttpResponse> {
+    let mut after = None;
+    let mut limit = PAGE_LIMIT;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        let value = percent_decode(value)
+            .ok_or_else(|| Error::InvalidArgErr(format!("invalid {}", name)))?;
+        match name {
+            "after" => after = Some(value),
+            "limit" => {
+                limit = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0 && n <= MAX_PAGE_LIMIT)
+                    .ok_or_else(|| Error::InvalidArgErr("invalid limit".to_owned()))?
+            }
+            _ => return Err(Error::InvalidArgErr(format!("unknown parameter {}", name))),
+        }
+    }
+    let keys: Vec<String> = engine
+        .keys()?
+        .into_iter()
+        .filter(|key| after.as_ref().is_none_or(|after| key > after))
+        .take(limit + 1)
+        .collect();
+    // fetching one extra key tells whether there is another page
+    let next = if keys.len() > limit { keys.get(limit - 1).cloned() } else { None };
+    let keys = &keys[..keys.len().min(limit)];
+    Ok(HttpResponse::json(200, json!({ "keys": keys, "next": next })))
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[test]
+fn test_read_request() {
+    let mut input = concat!(
+        "PUT /keys/a%20b HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n",
+        "Authorization: Bearer t\r\n\r\nvalue"
+    )
+    .as_bytes();
+    let request = HttpRequest::read_from(&mut input).unwrap().unwrap();
+    assert_eq!(request.method, "PUT");
+    assert_eq!(request.token.as_deref(), Some("t"));
+    assert_eq!(request.target, "/keys/a%20b");
+    assert_eq!(request.body, "value");
+    assert!(HttpRequest::read_from(&mut input).unwrap().is_none());
+    assert!(HttpRequest::read_from(&mut "GET / HTTP/1.1\r\n".as_bytes()).is_err());
+}
+#[test]
+fn test_percent_decode() {
+    assert_eq!(percent_decode("a%2Fb%20c").unwrap(), "a/b c");
+    assert!(percent_decode("%zz").is_none());
+    assert!(percent_decode("%2").is_none());
+}
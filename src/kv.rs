@@ -1,85 +1,353 @@
+use crate::backup::{self, Manifest, RestorePoint};
+use crate::batch::BatchCommand;
+use crate::diff;
 use crate::entry::Entry;
+use crate::metrics::{EngineGauges, Metrics};
+use crate::stats::{CompactionRun, Stats};
+use crate::record::{self, Damage, Record, RecordIter, RecordKind};
 use crate::error::Error;
 use crate::error::Result;
-use log::debug;
-use log::info;
+use tracing::{debug, info, instrument, warn};
 use serde::{Deserialize, Serialize};
 use tempfile::{NamedTempFile, TempPath};
-use tempfile::TempDir;
 use std::clone;
 use std::fs;
-use std::io::{Read, SeekFrom};
+use std::io::{BufReader, BufWriter, Read, SeekFrom};
 use std::io::Seek;
+use std::time::Instant;
 use std::mem::size_of;
 use std::mem::swap;
 use std::vec;
 use std::{
-    collections::HashMap,
-    fs::{File, OpenOptions},
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{File, OpenOptions, TryLockError},
     io::Write,
     path::Path,
+    sync::{Arc, Mutex, RwLock},
 };
+use dashmap::DashMap;
 use std::fs::write;
-use crate::KvsEngine;
 
-const DB_NAME: &str = "db.db";
-const INDEX_NAME: &str = "index.db";
+pub(crate) const DB_NAME: &str = "db.db";
+pub(crate) const INDEX_NAME: &str = "index.db";
 const COMPACT_SIZE: u64 = 1024 * 512;
+const COMPACT_NAME: &str = "db.db.compact";
+const COMPACTION_HISTORY: usize = 16;
+const COMPACTIONS_NAME: &str = "compactions.json";
+const LOCK_NAME: &str = "LOCK";
 
 // should use bitcask model to organize data
 // hashmap(in memory) K(String) V:(offset)
 //
-// #[derive(Serialize, Deserialize, Debug)]
+// Clones share the same store: reads go through the shared index and positional reads
+// on shared handles, so they run in parallel, while writes are serialized by `writer`.
+#[derive(Clone)]
 pub struct KvStore {
-    index: HashMap<String, Entry>,
-	back_index: HashMap<String, Entry>,
-    db: File,
+    index: Arc<DashMap<String, Entry>>,
+    // read handles by generation, a compaction adds the new one before retiring the old
+    readers: Arc<RwLock<HashMap<u64, Arc<File>>>>,
+    writer: Arc<Mutex<LogWriter>>,
     path: String,
-    compact_times: u16,
+}
+
+struct LogWriter {
+    db: File,
+    gen: u64,
+    // seq of the last record written, each record gets the next one
+    seq: u64,
+    // where the last record starts, index.db is checked against it on open
+    tail: u64,
+    // db size that triggers the next compaction
+    compact_size: u64,
+    compactions: CompactionHistory,
+    // kept here so index.db is written once, when the last clone goes away
+    index: Arc<DashMap<String, Entry>>,
+    index_path: String,
+    quotas: Vec<Quota>,
+    // where compactions, fsyncs and the engine gauges are reported, if anywhere
+    metrics: Option<Arc<Metrics>>,
+    // holds the directory lock until the last clone goes away
+    _lock: File,
+}
+
+// what index.db holds. It is only used on open if the log still ends with the
+// record it was written after, otherwise the index is rebuilt from the log.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct IndexFile {
+    pub db_len: u64,
+    // start and seq of the last record, unused while the log has none
+    pub tail: u64,
+    pub seq: u64,
+    pub entries: BTreeMap<String, Entry>,
+}
+
+// what compactions.json holds, so `stats` counts the compactions of earlier runs too.
+// It is kept apart from index.db, which is thrown away whenever it is stale.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct CompactionHistory {
+    count: u64,
+    // the last few runs, oldest first
+    recent: VecDeque<CompactionRun>,
+}
+
+// live bytes, keys plus values, allowed under a key prefix
+struct Quota {
+    prefix: String,
+    limit: u64,
+    used: u64,
 }
 
 impl KvStore {
     pub fn new(path: &Path) -> KvStore {
-        let db_file = OpenOptions::new()
-            .create(true)
-			.read(true)
-			.write(true)
-            .open(path.join(Path::new(DB_NAME)))
-            .unwrap();
-        KvStore {
-            index: HashMap::new(),
-			back_index: HashMap::new(),
-            db: db_file,
-            path: path.to_string_lossy().to_string(),
-            compact_times: 0,
-        }
-    }
-    pub fn set(&mut self, key: String, val: String) -> Result<()> {
-		self.db.seek(std::io::SeekFrom::End(0)).unwrap();
-		let val_bytes = val.as_bytes();
-		self.index.insert(key, Entry::get_entry(&self.db, val_bytes));
-		self.db.write_all(val_bytes).unwrap();
-		if self.db.seek(std::io::SeekFrom::End(0)).unwrap() > COMPACT_SIZE {
-			self.compact();
-		}
+        let lock = lock_dir(path).unwrap();
+        let db_file = open_log(path).unwrap();
+        KvStore::from_parts(path, lock, db_file, HashMap::new(), 0, 0).unwrap()
+    }
+    fn from_parts(
+        path: &Path,
+        lock: File,
+        db: File,
+        index: HashMap<String, Entry>,
+        seq: u64,
+        tail: u64,
+    ) -> Result<KvStore> {
+        let path = path.to_string_lossy().to_string();
+        let reader = File::open(Path::new(&path).join(DB_NAME))?;
+        let index: Arc<DashMap<String, Entry>> = Arc::new(index.into_iter().collect());
+        let compactions = read_compactions(Path::new(&path))?;
+        let writer = LogWriter {
+            db,
+            gen: 0,
+            seq,
+            tail,
+            compact_size: COMPACT_SIZE,
+            compactions,
+            index: Arc::clone(&index),
+            index_path: Path::new(&path).join(INDEX_NAME).to_string_lossy().to_string(),
+            quotas: vec![],
+            metrics: None,
+            _lock: lock,
+        };
+        let mut readers = HashMap::new();
+        readers.insert(0, Arc::new(reader));
+        Ok(KvStore {
+            index,
+            readers: Arc::new(RwLock::new(readers)),
+            writer: Arc::new(Mutex::new(writer)),
+            path,
+        })
+    }
+    #[instrument(level = "debug", skip(self, val), fields(len = val.len()))]
+    pub fn set(&self, key: String, val: String) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let old_size = self.live_size(&key);
+        let new_size = (key.len() + val.len()) as u64;
+        writer.check_quotas(&key, old_size, new_size)?;
+        writer.seq += 1;
+        let record = Record::set(writer.seq, key, val);
+        let position = writer.append(&record)?;
+        writer.tail = position;
+        writer.charge_quotas(&record.key, old_size, new_size);
+        let entry = Entry::new(writer.gen, position + record.value_offset(), record.value.len());
+        self.index.insert(record.key, entry);
+        if writer.db.metadata()?.len() > writer.compact_size {
+            self.compact_locked(&mut writer)?;
+        }
         Ok(())
     }
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.index.get(&key) {
-            None => Ok(None),
-            Some(entry) => {
-				let val = Entry::get_string(&mut self.db, entry)?;
-				Ok(Some(val))
-			}
+    #[instrument(level = "debug", skip(self))]
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        loop {
+            let entry = match self.index.get(&key) {
+                None => return Ok(None),
+                Some(entry) => entry.value().clone(),
+            };
+            let reader = self.readers.read().unwrap().get(&entry.gen()).cloned();
+            // a compaction may retire the generation between the two lookups,
+            // the index already points into the new one then
+            if let Some(reader) = reader {
+                return Ok(Some(Entry::get_string(&reader, &entry)?));
+            }
         }
     }
-    pub fn remove(&mut self, key: String) -> Result<()> {
+    #[instrument(level = "debug", skip(self))]
+    pub fn remove(&self, key: String) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
         if !self.index.contains_key(&key) {
             return Err(Error::KeyNotExistErr);
         }
+        let old_size = self.live_size(&key);
+        writer.seq += 1;
+        let seq = writer.seq;
+        writer.tail = writer.append(&Record::remove(seq, key.clone()))?;
+        writer.charge_quotas(&key, old_size, 0);
         self.index.remove(&key);
         Ok(())
     }
+    // append every set and remove of `commands` as one batch record under the writer
+    // lock, or nothing if a remove misses its key or a quota would be exceeded. Gets are
+    // skipped. The record has one checksum, so a crash mid-write loses the whole batch.
+    pub fn write_batch(&self, commands: &[BatchCommand]) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // live size of each key the batch wrote so far, `None` once removed
+        let mut pending: HashMap<&str, Option<u64>> = HashMap::new();
+        let mut charges = vec![];
+        let mut records = vec![];
+        let mut failure = None;
+        for command in commands {
+            let (key, record) = match command {
+                BatchCommand::Get { .. } => continue,
+                BatchCommand::Set { key, value } => (key, Record::set(0, key.clone(), value.clone())),
+                BatchCommand::Remove { key } => (key, Record::remove(0, key.clone())),
+            };
+            let old_size = match pending.get(key.as_str()) {
+                Some(size) => *size,
+                None if self.index.contains_key(key) => Some(self.live_size(key)),
+                None => None,
+            };
+            let new_size = match record.kind {
+                RecordKind::Set => Some((key.len() + record.value.len()) as u64),
+                _ => None,
+            };
+            if old_size.is_none() && new_size.is_none() {
+                failure = Some(Error::KeyNotExistErr);
+                break;
+            }
+            let (old, new) = (old_size.unwrap_or(0), new_size.unwrap_or(0));
+            if let Err(e) = writer.check_quotas(key, old, new) {
+                failure = Some(e);
+                break;
+            }
+            writer.charge_quotas(key, old, new);
+            charges.push((key, old, new));
+            pending.insert(key, new_size);
+            records.push(record);
+        }
+        if failure.is_none() && records.is_empty() {
+            return Ok(());
+        }
+        for (i, record) in records.iter_mut().enumerate() {
+            record.seq = writer.seq + i as u64 + 1;
+        }
+        let batch = Record::batch(records);
+        let written = match failure {
+            Some(e) => Err(e),
+            None => writer.append(&batch),
+        };
+        let position = match written {
+            Ok(position) => position,
+            Err(e) => {
+                for (key, old, new) in charges.into_iter().rev() {
+                    writer.charge_quotas(key, new, old);
+                }
+                return Err(e);
+            }
+        };
+        writer.seq = batch.seq;
+        writer.tail = position;
+        for (position, record) in batch.flatten(position) {
+            if record.kind == RecordKind::Set {
+                let entry = Entry::new(writer.gen, position + record.value_offset(), record.value.len());
+                self.index.insert(record.key, entry);
+            } else {
+                self.index.remove(&record.key);
+            }
+        }
+        if writer.db.metadata()?.len() > writer.compact_size {
+            self.compact_locked(&mut writer)?;
+        }
+        Ok(())
+    }
+    // cap the live bytes, keys plus values, under `prefix`. A set that would grow
+    // the prefix past `limit` fails with `LimitExceededErr`. Quotas are not persisted,
+    // whoever opens the store sets them again.
+    pub fn set_quota(&self, prefix: &str, limit: u64) {
+        let mut writer = self.writer.lock().unwrap();
+        let used = self
+            .index
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix))
+            .map(|entry| (entry.key().len() + entry.value().value_len()) as u64)
+            .sum();
+        writer.quotas.retain(|quota| quota.prefix != prefix);
+        writer.quotas.push(Quota {
+            prefix: prefix.to_owned(),
+            limit,
+            used,
+        });
+    }
+    pub fn remove_quota(&self, prefix: &str) {
+        let mut writer = self.writer.lock().unwrap();
+        writer.quotas.retain(|quota| quota.prefix != prefix);
+    }
+    // `(used, limit)` of the quota on `prefix`
+    pub fn quota_usage(&self, prefix: &str) -> Option<(u64, u64)> {
+        let writer = self.writer.lock().unwrap();
+        writer
+            .quotas
+            .iter()
+            .find(|quota| quota.prefix == prefix)
+            .map(|quota| (quota.used, quota.limit))
+    }
+    // report compaction and fsync latencies and the engine gauges into `metrics`
+    pub fn set_metrics(&self, metrics: Arc<Metrics>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        metrics.set_engine_gauges(EngineGauges::from(&self.stats_locked(&writer)?));
+        writer.metrics = Some(metrics);
+        Ok(())
+    }
+    // refresh the engine gauges, e.g. right before the metrics are rendered
+    pub fn update_metrics(&self) -> Result<()> {
+        let writer = self.writer.lock().unwrap();
+        writer.update_gauges(self)
+    }
+    pub fn stats(&self) -> Result<Stats> {
+        let writer = self.writer.lock().unwrap();
+        self.stats_locked(&writer)
+    }
+    fn stats_locked(&self, writer: &LogWriter) -> Result<Stats> {
+        let mut stats = Stats {
+            segments: self.readers.read().unwrap().len() as u64,
+            compactions: writer.compactions.count,
+            recent_compactions: writer.compactions.recent.iter().copied().collect(),
+            ..Stats::default()
+        };
+        for entry in self.index.iter() {
+            let key_len = entry.key().len() as u64;
+            stats.keys += 1;
+            stats.live_bytes += record::HEADER_LEN + key_len + entry.value().value_len() as u64;
+            // the key's heap buffer plus the map slot holding the key and entry
+            stats.index_bytes += key_len + (size_of::<String>() + size_of::<Entry>()) as u64;
+        }
+        stats.dead_bytes = writer
+            .db
+            .metadata()?
+            .len()
+            .saturating_sub(record::LOG_HEADER_LEN + stats.live_bytes);
+        Ok(stats)
+    }
+    // whether the set at `position` is the one the index points at
+    fn is_live(&self, position: u64, record: &Record) -> bool {
+        record.kind == RecordKind::Set
+            && self
+                .index
+                .get(&record.key)
+                .is_some_and(|entry| entry.position() == position + record.value_offset())
+    }
+    fn live_size(&self, key: &str) -> u64 {
+        self.index
+            .get(key)
+            .map_or(0, |entry| (key.len() + entry.value_len()) as u64)
+    }
+    // every live key, sorted
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.index.iter().map(|entry| entry.key().clone()).collect();
+        keys.sort();
+        keys
+    }
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains_key(key)
+    }
     pub fn get_db_path(&self) -> String {
         let mut p= self.path.clone();
 		p.push_str("/");
@@ -92,137 +360,482 @@ impl KvStore {
 		p.push_str(INDEX_NAME);
 		p
 	}
-	// use 
-	pub fn compact(&mut self) {
-        let binding = TempDir::new().unwrap();
-        let back_path = binding.path();
-        self.snapshot(back_path).unwrap();
-        let mut back_store = KvStore::open(back_path).unwrap();
-		let mut keys:Vec<String> = vec![];
-		for (key, _) in self.index.iter() {
-			keys.push(key.clone());
-		}
-        self.db.set_len(0).unwrap();
-        for key in keys {
-            let mut val = back_store.get(key.clone()).unwrap().unwrap();
-            self.set(key, val).unwrap();
+    // make every write so far durable and persist the index. Until the next write the
+    // store reopens from that index without replaying the log, also when the process is
+    // stopped without running destructors; after it open sees the index is stale and
+    // replays the log instead.
+    pub fn flush(&self) -> Result<()> {
+        let writer = self.writer.lock().unwrap();
+        let started = Instant::now();
+        writer.db.sync_all()?;
+        if let Some(metrics) = &writer.metrics {
+            metrics.observe_fsync(started.elapsed());
         }
-	}
-    pub fn snapshot(&mut self, path: &Path) -> Result<()> {
-        let mut index_pb = path.join(INDEX_NAME);
-        let index_path = index_pb.as_path();
-        let mut db_pb = path.join(DB_NAME);
-        let db_path = db_pb.as_path();
-        let mut db_file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
+        writer.write_index()
+    }
+    // rewrite the live records into a new db. While a chain of incremental backups
+    // is running, records newer than its last backup are kept as well so the next
+    // increment still finds the full history.
+    pub fn compact(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        self.compact_locked(&mut writer)
+    }
+    #[instrument(level = "info", skip_all, fields(gen = writer.gen))]
+    fn compact_locked(&self, writer: &mut LogWriter) -> Result<()> {
+        let started = Instant::now();
+        let bytes_before = writer.db.metadata()?.len();
+        let retain_seq = backup::read_backup_seq(Path::new(&self.path))?;
+        let compact_path = Path::new(&self.path).join(COMPACT_NAME);
+        let mut compacted = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&compact_path)?,
+        );
+        let gen = writer.gen + 1;
+        let mut moved = vec![];
+        compacted.write_all(&record::LOG_MAGIC)?;
+        let mut position = record::LOG_HEADER_LEN;
+        let mut tail = 0;
+        writer.db.seek(SeekFrom::Start(0))?;
+        for item in RecordIter::log(BufReader::new(&writer.db))? {
+            let (old_position, record) = item?;
+            // records the running backup chain still needs are kept whole, a batch
+            // stays one record, otherwise only the live sets are copied
+            let kept = if retain_seq.is_some_and(|seq| record.seq > seq) {
+                vec![(old_position, record)]
+            } else {
+                record
+                    .flatten(old_position)
+                    .into_iter()
+                    .filter(|(position, record)| self.is_live(*position, record))
+                    .collect()
+            };
+            for (old_position, record) in kept {
+                for (member_position, member) in record.clone().flatten(old_position) {
+                    if self.is_live(member_position, &member) {
+                        let new_position = position + (member_position - old_position);
+                        let entry = Entry::new(gen, new_position + member.value_offset(), member.value.len());
+                        moved.push((member.key, entry));
+                    }
+                }
+                compacted.write_all(&record.encode())?;
+                tail = position;
+                position += record.encoded_len();
+            }
+        }
+        compacted.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&compact_path, self.get_db_path())?;
+        writer.db = OpenOptions::new()
             .read(true)
-            .open(db_path).unwrap();
-        let file_size = self.db.metadata().unwrap().len();
-        let mut index_file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
             .write(true)
-            .read(true)
-            .open(index_path).unwrap();
+            .open(self.get_db_path())?;
 
-        let serialized = serde_json::to_string(&self.index).unwrap();
-        index_file.write_all(serialized.as_bytes()).unwrap();
+        // readers holding an entry of the old generation can still finish on the old
+        // handle, it is only retired once every entry points into the new file
+        let reader = Arc::new(File::open(self.get_db_path())?);
+        self.readers.write().unwrap().insert(gen, reader);
+        for (key, entry) in moved {
+            self.index.insert(key, entry);
+        }
+        self.readers.write().unwrap().remove(&writer.gen);
+        writer.gen = gen;
+        writer.tail = tail;
+        writer.compactions.count += 1;
+        if writer.compactions.recent.len() == COMPACTION_HISTORY {
+            writer.compactions.recent.pop_front();
+        }
+        let run = CompactionRun {
+            timestamp: record::now_millis(),
+            duration_ms: started.elapsed().as_millis() as u64,
+            bytes_before,
+            bytes_after: position,
+        };
+        info!(run.bytes_before, run.bytes_after, run.duration_ms, "compacted");
+        writer.compactions.recent.push_back(run);
+        backup::write_file(
+            Path::new(&self.path),
+            COMPACTIONS_NAME,
+            &serde_json::to_vec(&writer.compactions)?,
+        )?;
+        if let Some(metrics) = &writer.metrics {
+            metrics.observe_compaction(started.elapsed());
+        }
+        writer.update_gauges(self)?;
+        writer.compact_size = COMPACT_SIZE.max(position * 2);
+        // every position moved, don't leave an index.db pointing into the old file
+        writer.write_index()
+    }
+    pub fn snapshot(&self, path: &Path) -> Result<()> {
+        self.backup(path)
+    }
+    // hot backup: the db is copied up to its current length through a separate
+    // handle, so the store stays usable and the copy matches the index taken with it.
+    // A full backup ends any chain of incremental backups, compaction stops keeping
+    // history for it.
+    pub fn backup(&self, dir: &Path) -> Result<()> {
+        self.backup_full(dir, false)
+    }
+    fn backup_full(&self, dir: &Path, start_chain: bool) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        backup::remove_manifest(dir)?;
+        let (seq, db_len, db, index) = {
+            let writer = self.writer.lock().unwrap();
+            // under the lock, so no compaction drops a record the chain needs in between
+            if start_chain {
+                backup::write_backup_seq(Path::new(&self.path), writer.seq)?;
+            } else {
+                backup::remove_backup_seq(Path::new(&self.path))?;
+            }
+            let index = writer.index_file()?;
+            // a handle opened under the lock keeps reading this file even if a
+            // compaction replaces db.db while the copy runs
+            (writer.seq, index.db_len, File::open(self.get_db_path())?, index)
+        };
+        let mut manifest = Manifest {
+            seq,
+            timestamp: record::now_millis(),
+            ..Manifest::default()
+        };
+        manifest.files.push(backup::copy_file(db, dir, DB_NAME, db_len)?);
+        manifest.files.push(backup::write_file(dir, INDEX_NAME, &serde_json::to_vec(&index)?)?);
+        backup::write_manifest(dir, &manifest)
+    }
+    // append the records written since the last backup in `dir` as a new increment.
+    // A `dir` without a backup gets a full one that starts the chain: from then on
+    // compaction keeps every record newer than the chain's last backup.
+    pub fn backup_incremental(&self, dir: &Path) -> Result<()> {
+        if !backup::has_manifest(dir) {
+            return self.backup_full(dir, true);
+        }
+        let chain = backup::read_chain(dir)?;
+        let base_seq = chain.last().map_or(0, |(_, m)| m.seq);
+        let (seq, db_len, db) = {
+            let writer = self.writer.lock().unwrap();
+            (writer.seq, writer.db.metadata()?.len(), File::open(self.get_db_path())?)
+        };
+        let retain_seq = backup::read_backup_seq(Path::new(&self.path))?;
+        if seq < base_seq || retain_seq.is_none_or(|seq| seq > base_seq) {
+            return Err(Error::BackupErr(format!(
+                "{:?} is not this store's running backup chain, start a new one in an empty directory",
+                dir
+            )));
+        }
+        if seq == base_seq {
+            return Ok(());
+        }
 
-        let mut buffer = Vec::with_capacity(file_size as usize);
-        self.db.seek(SeekFrom::Start(0)).unwrap();
-        self.db.read_to_end(&mut buffer).unwrap();
-        db_file.write_all(&buffer).unwrap();
-        Ok(())
+        let incr_dir = backup::increment_dir(dir, chain.len());
+        fs::create_dir_all(&incr_dir)?;
+        backup::remove_manifest(&incr_dir)?;
+        let mut records = record::LOG_MAGIC.to_vec();
+        for item in RecordIter::log(BufReader::new(db.take(db_len)))? {
+            let (_, record) = item?;
+            if record.seq > base_seq {
+                records.extend_from_slice(&record.encode());
+            }
+        }
+        let manifest = Manifest {
+            files: vec![backup::write_file(&incr_dir, DB_NAME, &records)?],
+            seq,
+            timestamp: record::now_millis(),
+            base_seq: Some(base_seq),
+        };
+        backup::write_manifest(&incr_dir, &manifest)?;
+        backup::write_backup_seq(Path::new(&self.path), seq)
     }
-    pub fn compare(&mut self, other: &mut Self) -> bool {
-        let mut keys = vec![];
-        for (key, _) in &self.index {
-            keys.push(key.clone());
+    pub fn restore(backup_dir: &Path, path: &Path) -> Result<KvStore> {
+        KvStore::restore_until(backup_dir, path, RestorePoint::Latest, false)
+    }
+    // restore the full backup in `backup_dir` and replay its increments up to `point`.
+    // A `path` that already holds a store is only replaced with `overwrite`.
+    pub fn restore_until(
+        backup_dir: &Path,
+        path: &Path,
+        point: RestorePoint,
+        overwrite: bool,
+    ) -> Result<KvStore> {
+        let chain = backup::read_chain(backup_dir)?;
+        for (dir, manifest) in &chain {
+            backup::verify(dir, manifest)?;
+        }
+        if point.precedes(&chain[0].1) {
+            return Err(Error::BackupErr(format!(
+                "{:?} is older than the full backup",
+                point
+            )));
+        }
+        // held from before the first file is touched until the restored store owns it,
+        // so a store open on `path` is never written under
+        fs::create_dir_all(path)?;
+        let lock = lock_dir(path)?;
+        if !overwrite && holds_data(path)? {
+            return Err(Error::BackupErr(format!(
+                "{:?} already holds a store, restore with overwrite to replace it",
+                path
+            )));
+        }
+        // the replaced store's backups and compactions say nothing about the restored one
+        backup::remove_backup_seq(path)?;
+        match fs::remove_file(path.join(COMPACTIONS_NAME)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(Error::IoErr(e)),
+            _ => {}
         }
-        for k in keys {
-            let val = self.get(k.clone()).unwrap().unwrap();
-            let back_val = other.get(k.clone()).unwrap().unwrap();
-            if val != back_val {
-                return false;
+        if chain.len() == 1 && point == RestorePoint::Latest {
+            backup::restore_files(backup_dir, &chain[0].1, path)?;
+            return KvStore::open_locked(path, lock);
+        }
+
+        let mut db = record::LOG_MAGIC.to_vec();
+        for (dir, _) in &chain {
+            for item in RecordIter::log(BufReader::new(File::open(dir.join(DB_NAME))?))? {
+                let (_, record) = item?;
+                if point.includes(&record) {
+                    db.extend_from_slice(&record.encode());
+                }
             }
         }
-        true
+        backup::write_file(path, DB_NAME, &db)?;
+        // no index.db, so open rebuilds the index from the replayed log
+        match fs::remove_file(path.join(INDEX_NAME)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(Error::IoErr(e)),
+            _ => {}
+        }
+        KvStore::open_locked(path, lock)
+    }
+    // true when both stores hold exactly the same pairs
+    pub fn compare(&self, other: &Self) -> Result<bool> {
+        Ok(diff::diff(self, other, |_| Ok(()))?.is_empty())
     }
 }
 
 impl KvStore {
-    fn load(index: &mut File, path: &Path) -> Result<KvStore> {
-        let mut buf = String::new();
-        index.read_to_string(&mut buf).unwrap_or_else(|err| {
-            println!("failed to read_to_string");
-            0
-        });
-        if buf.is_empty() {
-            Ok(KvStore::new(path))
+    fn load(path: &Path, lock: File) -> Result<KvStore> {
+        let db = open_log(path)?;
+        if let Some(index) = KvStore::read_index(path, &db)? {
+            let entries = index.entries.into_iter().collect();
+            return KvStore::from_parts(path, lock, db, entries, index.seq, index.tail);
+        }
+        let (rebuilt, seq, tail) = KvStore::replay(&db)?;
+        info!(keys = rebuilt.len(), "recovered the index from the log");
+        KvStore::from_parts(path, lock, db, rebuilt, seq, tail)
+    }
+    // index.db if it was written after the last record of the log, `None` when it is
+    // missing, unreadable or stale, e.g. left by a process that died after more writes
+    fn read_index(path: &Path, db: &File) -> Result<Option<IndexFile>> {
+        let buf = match fs::read(path.join(INDEX_NAME)) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::IoErr(e)),
+        };
+        let index: IndexFile = match serde_json::from_slice(&buf) {
+            Ok(index) => index,
+            Err(err) => {
+                if !buf.is_empty() {
+                    warn!(%err, "index unreadable, rebuilding it from the log");
+                }
+                return Ok(None);
+            }
+        };
+        let db_len = db.metadata()?.len();
+        let current = if index.db_len != db_len {
+            false
+        } else if db_len == record::LOG_HEADER_LEN {
+            index.entries.is_empty()
         } else {
-            let index: HashMap<String, Entry> = serde_json::from_str(buf.as_str()).unwrap();
-            let db = OpenOptions::new()
-                .create(true)
-                .read(true)
-                .write(true)
-                .open(path.join(Path::new(DB_NAME)).as_path())
-                .unwrap();
-            Ok(KvStore {
-                index,
-				back_index: HashMap::new(),
-                db: db,
-                path: path.to_string_lossy().to_string(),
-                compact_times: 0,
-            })
+            let mut reader = BufReader::new(db);
+            reader.seek(SeekFrom::Start(index.tail))?;
+            match Record::decode(&mut reader) {
+                Ok(Some(record)) => {
+                    record.seq == index.seq && index.tail + record.encoded_len() == db_len
+                }
+                _ => false,
+            }
+        };
+        if !current {
+            info!("index is stale, rebuilding it from the log");
+            return Ok(None);
         }
+        Ok(Some(index))
     }
+    // rebuild the index from the log, later records win. A torn record at the end, left
+    // by a crash mid-append, is cut off; damage anywhere else is left to `fsck`.
+    fn replay(db: &File) -> Result<(HashMap<String, Entry>, u64, u64)> {
+        let mut index = HashMap::new();
+        let mut seq = 0;
+        let mut tail = 0;
+        let mut reader = BufReader::new(db);
+        reader.seek(SeekFrom::Start(0))?;
+        let mut records = RecordIter::log(reader)?;
+        loop {
+            let start = records.position();
+            let (position, record) = match records.next() {
+                None => break,
+                Some(Ok(item)) => item,
+                Some(Err(Error::CorruptedRecordErr(reason))) => {
+                    if Damage::classify(db, start, records.position())? != Damage::TornTail {
+                        return Err(Error::CorruptedRecordErr(format!(
+                            "damaged record at {}: {}, `kvs fsck --repair` skips it",
+                            start, reason
+                        )));
+                    }
+                    warn!(position = start, %reason, "the log ends in a torn record, truncating it");
+                    db.set_len(start)?;
+                    db.sync_all()?;
+                    break;
+                }
+                Some(Err(e)) => return Err(e),
+            };
+            seq = seq.max(record.seq);
+            tail = position;
+            for (position, record) in record.flatten(position) {
+                if record.kind == RecordKind::Set {
+                    let entry = Entry::new(0, position + record.value_offset(), record.value.len());
+                    index.insert(record.key, entry);
+                } else {
+                    index.remove(&record.key);
+                }
+            }
+        }
+        Ok((index, seq, tail))
+    }
+    #[instrument(level = "info", err(Debug))]
     pub fn open(path: &Path) -> Result<KvStore> {
-        let index_path = path.join(Path::new(INDEX_NAME));
-        let index_res = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true) // Create the file if it doesn't exist
-            .open(index_path.as_path());
+        KvStore::open_locked(path, lock_dir(path)?)
+    }
+    fn open_locked(path: &Path, lock: File) -> Result<KvStore> {
+        KvStore::load(path, lock)
+    }
+}
 
-        match index_res {
-            Ok(mut index) => {
-                let store = KvStore::load(&mut index, path)?;
-                Ok(store)
+impl LogWriter {
+    // shrinking writes are let through even over the limit, so a full prefix can be cleaned up
+    fn check_quotas(&self, key: &str, old_size: u64, new_size: u64) -> Result<()> {
+        if new_size <= old_size {
+            return Ok(());
+        }
+        for quota in self.quotas.iter().filter(|quota| key.starts_with(&quota.prefix)) {
+            if quota.used - old_size + new_size > quota.limit {
+                return Err(Error::LimitExceededErr(format!(
+                    "quota of {} bytes on {:?} exceeded",
+                    quota.limit, quota.prefix
+                )));
             }
-            Err(e) => {
-                println!("open file: {:?} error: {:?}", index_path.as_path(), e);
-                Err(Error::OpenFileErr)
+        }
+        Ok(())
+    }
+    fn charge_quotas(&mut self, key: &str, old_size: u64, new_size: u64) {
+        for quota in self.quotas.iter_mut().filter(|quota| key.starts_with(&quota.prefix)) {
+            quota.used = quota.used - old_size + new_size;
+        }
+    }
+    fn append(&mut self, record: &Record) -> Result<u64> {
+        self.append_bytes(&record.encode())
+    }
+    fn append_bytes(&mut self, buf: &[u8]) -> Result<u64> {
+        let position = self.db.seek(SeekFrom::End(0))?;
+        if let Err(e) = self.db.write_all(buf) {
+            // don't leave half a record for the next append to land behind
+            if let Err(err) = self.db.set_len(position) {
+                warn!(%err, position, "failed to cut off a partial append");
             }
+            return Err(e.into());
         }
+        Ok(position)
     }
-}
-
-impl Drop for KvStore {
-    fn drop(&mut self) {
-        println!("drop called and index file is {:?}", self.get_index_path());
-        let serialized = serde_json::to_string(&self.index).unwrap();
+    fn update_gauges(&self, store: &KvStore) -> Result<()> {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_engine_gauges(EngineGauges::from(&store.stats_locked(self)?));
+        }
+        Ok(())
+    }
+    fn index_file(&self) -> Result<IndexFile> {
+        Ok(IndexFile {
+            db_len: self.db.metadata()?.len(),
+            tail: self.tail,
+            seq: self.seq,
+            entries: index_snapshot(&self.index),
+        })
+    }
+    fn write_index(&self) -> Result<()> {
+        let serialized = serde_json::to_string(&self.index_file()?)?;
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(self.get_index_path())
-            .unwrap();
-        file.write_all(serialized.as_bytes()).unwrap();
+            .open(&self.index_path)?;
+        file.write_all(serialized.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+// take the lock on the data directory, a store is only ever open in one place.
+// The lock is released by the OS when the file is closed, also when the process dies.
+pub(crate) fn lock_dir(path: &Path) -> Result<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.join(LOCK_NAME))?;
+    match lock.try_lock() {
+        Ok(()) => Ok(lock),
+        Err(TryLockError::WouldBlock) => Err(Error::LockedErr(format!("{:?} is in use", path))),
+        Err(TryLockError::Error(e)) => Err(Error::IoErr(e)),
     }
 }
-impl KvsEngine for KvStore {
-    fn get(&mut self, key: String) -> String {
-        todo!()
+
+// open db.db in `path`, a new one starts with the log header
+fn open_log(path: &Path) -> Result<File> {
+    let mut db = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path.join(DB_NAME))?;
+    if db.metadata()?.len() == 0 {
+        db.write_all(&record::LOG_MAGIC)?;
+        db.sync_all()?;
     }
+    Ok(db)
+}
 
-    fn set(&mut self, key: String, val: String) {
-        todo!()
+// the compaction history of the store in `path`, empty if it never compacted
+fn read_compactions(path: &Path) -> Result<CompactionHistory> {
+    match fs::read(path.join(COMPACTIONS_NAME)) {
+        Ok(buf) => Ok(serde_json::from_slice(&buf).unwrap_or_else(|err| {
+            warn!(%err, "compaction history unreadable, starting a new one");
+            CompactionHistory::default()
+        })),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(CompactionHistory::default()),
+        Err(e) => Err(Error::IoErr(e)),
     }
+}
 
-    fn remove(&mut self, key: String) {
-        todo!()
+// whether `path` has a log with a record in it
+fn holds_data(path: &Path) -> Result<bool> {
+    match fs::metadata(path.join(DB_NAME)) {
+        Ok(metadata) => Ok(metadata.len() > record::LOG_HEADER_LEN),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(Error::IoErr(e)),
+    }
+}
+
+fn index_snapshot(index: &DashMap<String, Entry>) -> BTreeMap<String, Entry> {
+    index
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect()
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        debug!("drop called and index file is {:?}", self.index_path);
+        // a stale or missing index.db only costs the next open a replay
+        if let Err(err) = self.write_index() {
+            warn!(?err, "failed to write the index on close");
+        }
     }
 }
 
@@ -240,12 +853,12 @@ mod test {
 		let path_string = "/Users/lee/Code/RustLearn/pingcap-talent-plan/project-1";
 		let p = Path::new(path_string);
         {
-            let mut store = KvStore::open(p).unwrap();
+            let store = KvStore::open(p).unwrap();
 			store.set("key".to_string(), "val".to_string()).unwrap();
             let val = store.get("key".to_owned()).unwrap().unwrap();
             assert_eq!(val, "val".to_owned());
         }
-		let mut store = KvStore::open(p).unwrap();
+		let store = KvStore::open(p).unwrap();
 		let val = store.get("key".to_owned()).unwrap().unwrap();
 		assert_eq!(val, "val".to_owned());
     }
@@ -263,27 +876,27 @@ mod test {
 	fn test_compaction() {
 		let path_string = "/Users/lee/Code/RustLearn/pingcap-talent-plan/project-1";
 		let p = Path::new(path_string);
-		let mut store = KvStore::open(p).unwrap();
+		let store = KvStore::open(p).unwrap();
 		store.set("key".to_string(), "val".to_string()).unwrap();
 		store.set("key".to_string(), "va2".to_string()).unwrap();
 		store.set("key".to_string(), "val3".to_string()).unwrap();
 		store.set("key".to_string(), "val4".to_string()).unwrap();
 		let before_size = get_dir_size(p);
-		store.compact();
+		store.compact().unwrap();
 		let after_size = get_dir_size(p);
 		assert!(before_size > after_size);
 	}
     #[test]
     fn test_snapshot() {
         let path = Path::new("/Users/lee/Code/RustLearn/pingcap-talent-plan/project-1");
-        let mut store = KvStore::new(path);
+        let store = KvStore::new(path);
         for i in 0..100 {
             let iter = i.to_string();
             store.set(iter.clone(), iter).unwrap();
         }
         let back_path = Path::new("/Users/lee/Code/RustLearn/pingcap-talent-plan/project-1/back");
         store.snapshot(back_path).unwrap();
-        let mut back_store = KvStore::open(back_path).unwrap();
+        let back_store = KvStore::open(back_path).unwrap();
         for i in 0..100 {
             let iter = i.to_string();
             let res = back_store.get(iter.clone()).unwrap().unwrap();
@@ -1,10 +1,51 @@
 pub use kv::KvStore;
-pub use error::Result;
+pub use error::{Error, Result};
 pub use utils::DeferDrop;
-pub use server::KvsEngine;
+pub use backup::RestorePoint;
+pub use fsck::{fsck, FsckMode, FsckReport};
+pub use upgrade::upgrade;
+pub use export::{DataFormat, ImportPolicy, ImportSummary};
+pub use migrate::{migrate, migrate_dir, MigrateSummary};
+pub use diff::{diff, DiffSummary, Difference};
+pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use protocol::{read_frame, write_frame, ErrorCode, Request, Response, CONNECTION_ID};
+pub use resp::{dispatch, read_command, Reply};
+pub use http::{route, HttpRequest, HttpResponse};
+pub use acl::{Access, Acl, Grant, Principal};
+pub use limit::{RateLimiter, TokenBucket};
+pub use metrics::{EngineGauges, Metrics};
+pub use stats::{CompactionRun, Stats};
+pub use logging::{init_logging, parse_level, LogConfig, LogFormat};
+pub use slowlog::{SlowEntry, SlowLog};
+pub use batch::{parse_batch, BatchCommand, BatchMode, BatchSummary, Outcome};
+pub use server::{KvsServer, Protocol, ServerConfig};
+pub use expire::Expiring;
+pub use client::KvsClient;
+pub use engines::{open_engine, prepare_data_dir, read_engine_marker, write_engine_marker, Engine, KvsEngine, SledKvsEngine};
 
 mod kv;
 mod error;
 mod utils;
 mod entry;
-mod server;
\ No newline at end of file
+mod engines;
+mod backup;
+mod record;
+mod fsck;
+mod upgrade;
+mod export;
+mod migrate;
+mod diff;
+mod thread_pool;
+mod protocol;
+mod resp;
+mod http;
+mod acl;
+mod limit;
+mod metrics;
+mod stats;
+mod logging;
+mod slowlog;
+mod batch;
+mod expire;
+mod server;
+mod client;
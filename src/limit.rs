@@ -0,0 +1,115 @@
+use crate::error::{Error, Result};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::Instant,
+};
+
+// refills at `rate` tokens a second up to `burst`
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            refilled: now,
+        }
+    }
+    // a request larger than the whole burst is let through once the bucket is full
+    // and leaves it in debt, otherwise it could never pass
+    pub fn try_take(&mut self, n: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+        if self.tokens < n.min(self.burst) {
+            return false;
+        }
+        self.tokens -= n;
+        true
+    }
+}
+
+// per client limits on requests and bytes a second, each allowing a one second burst
+pub struct RateLimiter {
+    requests_per_sec: Option<f64>,
+    bytes_per_sec: Option<f64>,
+    clients: Mutex<HashMap<String, ClientBuckets>>,
+}
+
+struct ClientBuckets {
+    requests: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_sec: Option<f64>, bytes_per_sec: Option<f64>) -> RateLimiter {
+        RateLimiter {
+            requests_per_sec,
+            bytes_per_sec,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+    // account one request of `bytes` from `client`, an identity or ip address
+    pub fn check(&self, client: &str, bytes: u64) -> Result<()> {
+        self.check_at(client, bytes, Instant::now())
+    }
+    fn check_at(&self, client: &str, bytes: u64, now: Instant) -> Result<()> {
+        let mut clients = self.clients.lock().unwrap();
+        let buckets = clients.entry(client.to_owned()).or_insert_with(|| ClientBuckets {
+            requests: self.requests_per_sec.map(|rate| TokenBucket::new(rate, rate, now)),
+            bytes: self.bytes_per_sec.map(|rate| TokenBucket::new(rate, rate, now)),
+        });
+        if let Some(requests) = &mut buckets.requests {
+            if !requests.try_take(1.0, now) {
+                return Err(Error::LimitExceededErr(format!("{} sends too many requests", client)));
+            }
+        }
+        if let Some(bytes_bucket) = &mut buckets.bytes {
+            if !bytes_bucket.try_take(bytes as f64, now) {
+                return Err(Error::LimitExceededErr(format!("{} sends too many bytes", client)));
+            }
+        }
+        Ok(())
+    }
+    // forget a client, e.g. when its last connection closes
+    pub fn remove(&self, client: &str) {
+        self.clients.lock().unwrap().remove(client);
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    use std::time::Duration;
+
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(10.0, 10.0, start);
+    for _ in 0..10 {
+        assert!(bucket.try_take(1.0, start));
+    }
+    assert!(!bucket.try_take(1.0, start));
+    assert!(bucket.try_take(1.0, start + Duration::from_millis(100)));
+    assert!(!bucket.try_take(1.0, start + Duration::from_millis(100)));
+    // an oversized take waits for a full bucket
+    assert!(!bucket.try_take(50.0, start + Duration::from_millis(500)));
+    assert!(bucket.try_take(50.0, start + Duration::from_secs(2)));
+    assert!(!bucket.try_take(1.0, start + Duration::from_secs(3)));
+}
+#[test]
+fn test_rate_limiter() {
+    let start = Instant::now();
+    let limiter = RateLimiter::new(Some(2.0), Some(100.0));
+    assert!(limiter.check_at("a", 10, start).is_ok());
+    assert!(limiter.check_at("a", 10, start).is_ok());
+    assert!(matches!(limiter.check_at("a", 10, start), Err(Error::LimitExceededErr(_))));
+    // clients have their own buckets
+    assert!(limiter.check_at("b", 100, start).is_ok());
+    assert!(limiter.check_at("b", 1, start).is_err());
+}
@@ -0,0 +1,164 @@
+use crate::error::{Error, Result};
+use crate::record;
+use serde_json::{json, Map, Value};
+use std::{
+    env, fmt,
+    fs::OpenOptions,
+    io,
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+};
+use tracing::{field::Field, Event, Level, Subscriber};
+use tracing_subscriber::{
+    field::Visit,
+    fmt::{
+        format::{self, FormatEvent, FormatFields},
+        FmtContext, FormattedFields,
+    },
+    registry::LookupSpan,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    // one human readable line per event, with its spans
+    Pretty,
+    // one json object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LogFormat> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(Error::InvalidArgErr(format!("unknown log format {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    pub level: Level,
+    pub format: LogFormat,
+    // append to this file instead of writing to stderr
+    pub file: Option<PathBuf>,
+}
+
+impl Default for LogConfig {
+    // stdout belongs to command output, so logs go to stderr and only warnings show
+    fn default() -> LogConfig {
+        LogConfig {
+            level: Level::WARN,
+            format: LogFormat::Pretty,
+            file: None,
+        }
+    }
+}
+
+impl LogConfig {
+    // the defaults overridden by KVS_LOG (the level), KVS_LOG_FORMAT and KVS_LOG_FILE
+    pub fn from_env() -> Result<LogConfig> {
+        let mut config = LogConfig::default();
+        if let Ok(level) = env::var("KVS_LOG") {
+            config.level = parse_level(&level)?;
+        }
+        if let Ok(format) = env::var("KVS_LOG_FORMAT") {
+            config.format = format.parse()?;
+        }
+        if let Ok(file) = env::var("KVS_LOG_FILE") {
+            config.file = Some(PathBuf::from(file));
+        }
+        Ok(config)
+    }
+}
+
+pub fn parse_level(s: &str) -> Result<Level> {
+    Level::from_str(s).map_err(|_| Error::InvalidArgErr(format!("unknown log level {}", s)))
+}
+
+// install the global subscriber, fails if one is already installed
+pub fn init_logging(config: &LogConfig) -> Result<()> {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(config.level)
+        .with_ansi(false);
+    let installed = match (&config.file, config.format) {
+        (None, LogFormat::Pretty) => builder.with_writer(io::stderr).try_init(),
+        (None, LogFormat::Json) => builder
+            .event_format(JsonFormat)
+            .with_writer(io::stderr)
+            .try_init(),
+        (Some(path), format) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let builder = builder.with_writer(Mutex::new(file));
+            match format {
+                LogFormat::Pretty => builder.try_init(),
+                LogFormat::Json => builder.event_format(JsonFormat).try_init(),
+            }
+        }
+    };
+    installed.map_err(|e| Error::InvalidArgErr(format!("logging is already set up: {}", e)))
+}
+
+// `{"timestamp": .., "level": .., "target": .., "spans": [{"name": .., "fields": ..}], "fields": {..}}`
+// span fields keep the text form the field formatter gave them
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = JsonFields(Map::new());
+        event.record(&mut fields);
+        let spans: Vec<Value> = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let extensions = span.extensions();
+                let span_fields = extensions
+                    .get::<FormattedFields<N>>()
+                    .map_or("", |fields| fields.fields.as_str());
+                json!({ "name": span.name(), "fields": span_fields })
+            })
+            .collect();
+        let metadata = event.metadata();
+        let line = json!({
+            "timestamp": record::now_millis(),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "spans": spans,
+            "fields": fields.0,
+        });
+        writeln!(writer, "{}", line)
+    }
+}
+
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), json!(value));
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), json!(value));
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), json!(value));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), json!(value));
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_owned(), json!(format!("{:?}", value)));
+    }
+}
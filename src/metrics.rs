@@ -0,0 +1,167 @@
+use crate::stats::Stats;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+// upper bounds of the latency buckets, in seconds
+const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    // per bucket, not cumulative, the last one counts everything above the largest bound
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let i = BUCKETS.iter().position(|&bound| secs <= bound).unwrap_or(BUCKETS.len());
+        self.counts[i] += 1;
+        self.sum += secs;
+    }
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        cumulative += self.counts[BUCKETS.len()];
+        let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, cumulative);
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{}}}", labels),
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, cumulative);
+    }
+}
+
+// values the engine reports, refreshed by whoever renders the metrics
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EngineGauges {
+    pub keys: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+}
+
+impl From<&Stats> for EngineGauges {
+    fn from(stats: &Stats) -> EngineGauges {
+        EngineGauges {
+            keys: stats.keys,
+            live_bytes: stats.live_bytes,
+            dead_bytes: stats.dead_bytes,
+        }
+    }
+}
+
+// process wide counters, rendered in the prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<String, (u64, u64, Histogram)>>,
+    active_connections: AtomicU64,
+    compactions: Mutex<Histogram>,
+    fsyncs: Mutex<Histogram>,
+    engine: Mutex<EngineGauges>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+    pub fn observe_request(&self, command: &str, duration: Duration, failed: bool) {
+        let mut requests = self.requests.lock().unwrap();
+        let (count, errors, latency) = requests.entry(command.to_owned()).or_default();
+        *count += 1;
+        if failed {
+            *errors += 1;
+        }
+        latency.observe(duration);
+    }
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+    pub fn observe_compaction(&self, duration: Duration) {
+        self.compactions.lock().unwrap().observe(duration);
+    }
+    pub fn observe_fsync(&self, duration: Duration) {
+        self.fsyncs.lock().unwrap().observe(duration);
+    }
+    pub fn set_engine_gauges(&self, gauges: EngineGauges) {
+        *self.engine.lock().unwrap() = gauges;
+    }
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let requests = self.requests.lock().unwrap().clone();
+        out.push_str("# TYPE kvs_requests_total counter\n");
+        for (command, (count, _, _)) in &requests {
+            let _ = writeln!(out, "kvs_requests_total{{command=\"{}\"}} {}", command, count);
+        }
+        out.push_str("# TYPE kvs_request_errors_total counter\n");
+        for (command, (_, errors, _)) in &requests {
+            let _ = writeln!(out, "kvs_request_errors_total{{command=\"{}\"}} {}", command, errors);
+        }
+        out.push_str("# TYPE kvs_request_duration_seconds histogram\n");
+        for (command, (_, _, latency)) in &requests {
+            let labels = format!("command=\"{}\",", command);
+            latency.render(&mut out, "kvs_request_duration_seconds", &labels);
+        }
+
+        let _ = writeln!(
+            out,
+            "# TYPE kvs_active_connections gauge\nkvs_active_connections {}",
+            self.active_connections.load(Ordering::Relaxed)
+        );
+        let engine = *self.engine.lock().unwrap();
+        for (name, value) in &[
+            ("kvs_keys", engine.keys),
+            ("kvs_live_bytes", engine.live_bytes),
+            ("kvs_dead_bytes", engine.dead_bytes),
+        ] {
+            let _ = writeln!(out, "# TYPE {} gauge\n{} {}", name, name, value);
+        }
+
+        out.push_str("# TYPE kvs_compaction_duration_seconds histogram\n");
+        self.compactions
+            .lock()
+            .unwrap()
+            .render(&mut out, "kvs_compaction_duration_seconds", "");
+        out.push_str("# TYPE kvs_fsync_duration_seconds histogram\n");
+        self.fsyncs.lock().unwrap().render(&mut out, "kvs_fsync_duration_seconds", "");
+        out
+    }
+}
+
+#[test]
+fn test_render() {
+    let metrics = Metrics::new();
+    metrics.observe_request("get", Duration::from_micros(50), false);
+    metrics.observe_request("get", Duration::from_millis(2), true);
+    metrics.observe_compaction(Duration::from_secs(10));
+    metrics.connection_opened();
+    metrics.set_engine_gauges(EngineGauges {
+        keys: 3,
+        live_bytes: 30,
+        dead_bytes: 7,
+    });
+    let out = metrics.render();
+    assert!(out.contains("kvs_requests_total{command=\"get\"} 2\n"));
+    assert!(out.contains("kvs_request_errors_total{command=\"get\"} 1\n"));
+    assert!(out.contains("kvs_request_duration_seconds_bucket{command=\"get\",le=\"0.0001\"} 1\n"));
+    assert!(out.contains("kvs_request_duration_seconds_bucket{command=\"get\",le=\"0.005\"} 2\n"));
+    assert!(out.contains("kvs_request_duration_seconds_count{command=\"get\"} 2\n"));
+    assert!(out.contains("kvs_compaction_duration_seconds_bucket{le=\"5\"} 0\n"));
+    assert!(out.contains("kvs_compaction_duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+    assert!(out.contains("kvs_compaction_duration_seconds_count 1\n"));
+    assert!(out.contains("kvs_active_connections 1\n"));
+    assert!(out.contains("kvs_dead_bytes 7\n"));
+}
@@ -0,0 +1,94 @@
+use crate::engines::{self, KvsEngine};
+use crate::error::{Error, Result};
+use std::path::Path;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MigrateSummary {
+    pub keys: u64,
+    // crc32 over every key and value in key order, equal on both sides after a migration
+    pub checksum: u32,
+}
+
+// copy every pair of `src` into the empty `dst`, then read `dst` back and compare
+pub fn migrate<S: KvsEngine, D: KvsEngine>(src: &S, dst: &D) -> Result<MigrateSummary> {
+    if !dst.keys()?.is_empty() {
+        return Err(Error::InvalidArgErr(
+            "the destination store is not empty".to_owned(),
+        ));
+    }
+    let mut copied = Digest::default();
+    for key in src.keys()? {
+        if let Some(val) = src.get(key.clone())? {
+            copied.update(&key, &val);
+            dst.set(key, val)?;
+        }
+    }
+    let copied = copied.finish();
+
+    let mut read_back = Digest::default();
+    for key in dst.keys()? {
+        if let Some(val) = dst.get(key.clone())? {
+            read_back.update(&key, &val);
+        }
+    }
+    let read_back = read_back.finish();
+    if copied != read_back {
+        return Err(Error::MigrateErr(format!(
+            "copied {:?} but the destination holds {:?}",
+            copied, read_back
+        )));
+    }
+    Ok(copied)
+}
+
+// migrate the `from` store in `src` into a new `to` store in `dst` and mark `dst` with its engine
+pub fn migrate_dir(from: &str, src: &Path, to: &str, dst: &Path) -> Result<MigrateSummary> {
+    if let Some(marker) = engines::read_engine_marker(src)? {
+        if marker != from {
+            return Err(Error::WrongEngineErr(marker));
+        }
+    }
+    if let Some(marker) = engines::read_engine_marker(dst)? {
+        if marker != to {
+            return Err(Error::WrongEngineErr(marker));
+        }
+    }
+    // opening a directory without a store would create an empty one there,
+    // an existing store is migrated even when it holds no keys
+    if !engines::holds_store(from, src) {
+        return Err(Error::MigrateErr(format!("{:?} holds no {} store", src, from)));
+    }
+    let summary = {
+        let src_engine = engines::open_engine(from, src)?;
+        let dst_engine = engines::open_engine(to, dst)?;
+        let summary = migrate(&src_engine, &dst_engine)?;
+        // the marker must not be written before the data it vouches for is durable
+        dst_engine.flush()?;
+        summary
+    };
+    engines::write_engine_marker(dst, to)?;
+    Ok(summary)
+}
+
+#[derive(Default)]
+struct Digest {
+    keys: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl Digest {
+    fn update(&mut self, key: &str, val: &str) {
+        // length prefixes keep ("ab", "c") and ("a", "bc") apart
+        self.hasher.update(&(key.len() as u64).to_le_bytes());
+        self.hasher.update(key.as_bytes());
+        self.hasher.update(&(val.len() as u64).to_le_bytes());
+        self.hasher.update(val.as_bytes());
+        self.keys += 1;
+    }
+    fn finish(self) -> MigrateSummary {
+        MigrateSummary {
+            keys: self.keys,
+            checksum: self.hasher.finalize(),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use crate::error::{Error, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    convert::TryInto,
+    io::{self, Read, Write},
+};
+
+// every message on the wire is framed as
+// | len u32 | id u64 | payload |
+// integers are little-endian, `len` counts the id and the payload, and the payload
+// is a json encoded `Request` or `Response`. A response carries the id of its
+// request, so a client can keep several requests in flight on one connection and
+// match the answers in whatever order they arrive.
+pub const FRAME_HEADER_LEN: usize = 12;
+// frames above this are rejected before anything is allocated for them
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+// the id of an error about the connection rather than one request, such as a busy
+// server. The server closes the connection after sending it.
+pub const CONNECTION_ID: u64 = u64::MAX;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Request {
+    // the first request on a connection when the server requires authentication
+    Auth { token: String },
+    Get { key: String },
+    Set { key: String, value: String },
+    Remove { key: String },
+    // stop accepting connections, finish in-flight requests and flush the engine
+    Shutdown,
+    // the engine statistics and the open connections, as text
+    Info,
+    // the most recent `count` entries of the slow log, newest first, one json
+    // `SlowEntry` a line
+    SlowLog { count: u64 },
+}
+
+impl Request {
+    // the label of its requests in the server metrics
+    pub fn command(&self) -> &'static str {
+        match self {
+            Request::Auth { .. } => "auth",
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Remove { .. } => "rm",
+            Request::Shutdown => "shutdown",
+            Request::Info => "info",
+            Request::SlowLog { .. } => "slowlog",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Response {
+    // the value for a get, `None` for a missing key and for set and remove
+    Ok(Option<String>),
+    Err { code: ErrorCode, message: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ErrorCode {
+    NotFound,
+    Corruption,
+    Locked,
+    InvalidArg,
+    Unauthenticated,
+    PermissionDenied,
+    Busy,
+    LimitExceeded,
+    Internal,
+}
+
+impl From<&Error> for ErrorCode {
+    fn from(err: &Error) -> ErrorCode {
+        match err {
+            Error::KeyNotExistErr => ErrorCode::NotFound,
+            Error::CorruptedRecordErr(_) | Error::BackupCorruptedErr(_) => ErrorCode::Corruption,
+            Error::LockedErr(_) => ErrorCode::Locked,
+            Error::InvalidArgErr(_) => ErrorCode::InvalidArg,
+            Error::AuthErr(_) => ErrorCode::Unauthenticated,
+            Error::PermissionDeniedErr(_) => ErrorCode::PermissionDenied,
+            Error::BusyErr => ErrorCode::Busy,
+            Error::LimitExceededErr(_) => ErrorCode::LimitExceeded,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<Result<Option<String>>> for Response {
+    fn from(result: Result<Option<String>>) -> Response {
+        match result {
+            Ok(value) => Response::Ok(value),
+            Err(e) => Response::Err {
+                code: ErrorCode::from(&e),
+                message: format!("{:?}", e),
+            },
+        }
+    }
+}
+
+impl From<Response> for Result<Option<String>> {
+    fn from(response: Response) -> Result<Option<String>> {
+        match response {
+            Response::Ok(value) => Ok(value),
+            Response::Err { code: ErrorCode::NotFound, .. } => Err(Error::KeyNotExistErr),
+            Response::Err { code: ErrorCode::Corruption, message } => {
+                Err(Error::CorruptedRecordErr(message))
+            }
+            Response::Err { code: ErrorCode::Locked, message } => Err(Error::LockedErr(message)),
+            Response::Err { code: ErrorCode::InvalidArg, message } => {
+                Err(Error::InvalidArgErr(message))
+            }
+            Response::Err { code: ErrorCode::Unauthenticated, message } => Err(Error::AuthErr(message)),
+            Response::Err { code: ErrorCode::PermissionDenied, message } => {
+                Err(Error::PermissionDeniedErr(message))
+            }
+            Response::Err { code: ErrorCode::Busy, .. } => Err(Error::BusyErr),
+            Response::Err { code: ErrorCode::LimitExceeded, message } => {
+                Err(Error::LimitExceededErr(message))
+            }
+            Response::Err { code: ErrorCode::Internal, message } => Err(Error::ProtocolErr(message)),
+        }
+    }
+}
+
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, id: u64, message: &T) -> Result<()> {
+    let payload = serde_json::to_vec(message)?;
+    let len = payload.len() + 8;
+    if len > MAX_FRAME_LEN as usize {
+        return Err(Error::ProtocolErr(format!("frame of {} bytes is too large", len)));
+    }
+    let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    buf.extend_from_slice(&(len as u32).to_le_bytes());
+    buf.extend_from_slice(&id.to_le_bytes());
+    buf.extend_from_slice(&payload);
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+// read the next frame, `Ok(None)` means the peer closed the connection between frames
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<(u64, T)>> {
+    let mut header = [0; FRAME_HEADER_LEN];
+    match reader.read_exact(&mut header[..1]) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    reader.read_exact(&mut header[1..])?;
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let id = u64::from_le_bytes(header[4..12].try_into().unwrap());
+    if len > MAX_FRAME_LEN {
+        return Err(Error::ProtocolErr(format!("frame of {} bytes is too large", len)));
+    }
+    if len < 8 {
+        return Err(Error::ProtocolErr(format!("frame of {} bytes is too short", len)));
+    }
+    let mut payload = vec![0; len as usize - 8];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((id, serde_json::from_slice(&payload)?)))
+}
+
+#[test]
+fn test_pipelined_frames() {
+    let mut buf = Vec::new();
+    write_frame(&mut buf, 1, &Request::Get { key: "a".to_owned() }).unwrap();
+    write_frame(&mut buf, 2, &Request::Remove { key: "b".to_owned() }).unwrap();
+    let mut reader = buf.as_slice();
+    let first: (u64, Request) = read_frame(&mut reader).unwrap().unwrap();
+    let second: (u64, Request) = read_frame(&mut reader).unwrap().unwrap();
+    assert_eq!(first, (1, Request::Get { key: "a".to_owned() }));
+    assert_eq!(second, (2, Request::Remove { key: "b".to_owned() }));
+    assert!(read_frame::<_, Request>(&mut reader).unwrap().is_none());
+}
+#[test]
+fn test_error_codes() {
+    let response = Response::from(Err::<Option<String>, _>(Error::KeyNotExistErr));
+    let mut buf = Vec::new();
+    write_frame(&mut buf, 7, &response).unwrap();
+    let (id, response): (u64, Response) = read_frame(&mut buf.as_slice()).unwrap().unwrap();
+    assert_eq!(id, 7);
+    let result: Result<Option<String>> = response.into();
+    assert!(matches!(result, Err(Error::KeyNotExistErr)));
+    assert!(read_frame::<_, Response>(&mut &buf[..5]).is_err());
+}
+#[test]
+fn test_permission_denied() {
+    let err = Error::PermissionDeniedErr("app may not Write shared/1".to_owned());
+    let response = Response::from(Err::<Option<String>, _>(err));
+    assert!(matches!(response, Response::Err { code: ErrorCode::PermissionDenied, .. }));
+    let result: Result<Option<String>> = response.into();
+    assert!(matches!(result, Err(Error::PermissionDeniedErr(_))));
+}
@@ -0,0 +1,436 @@
+use crate::entry::read_exact_at;
+use crate::error::{Error, Result};
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{self, Read},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// db.db starts with this magic, its last byte is the format version
+pub const LOG_MAGIC: [u8; 8] = *b"KVSLOG\0\x01";
+pub const LOG_HEADER_LEN: u64 = LOG_MAGIC.len() as u64;
+
+// every record in db.db is laid out as
+// | crc32 u32 | seq u64 | timestamp u64 | kind u8 | key_len u32 | val_len u32 | key | value |
+// integers are little-endian and the checksum covers everything after itself.
+// A batch has no key and its value is the encoded records it applies, so the one
+// checksum covers them all and a torn batch is dropped whole.
+pub const HEADER_LEN: u64 = 29;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Set,
+    Remove,
+    Batch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub seq: u64,
+    // milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub kind: RecordKind,
+    pub key: String,
+    pub value: String,
+    // the sets and removes of a batch, in order, empty for other kinds
+    pub members: Vec<Record>,
+}
+
+impl Record {
+    pub fn set(seq: u64, key: String, value: String) -> Record {
+        Record {
+            seq,
+            timestamp: now_millis(),
+            kind: RecordKind::Set,
+            key,
+            value,
+            members: vec![],
+        }
+    }
+    pub fn remove(seq: u64, key: String) -> Record {
+        Record {
+            seq,
+            timestamp: now_millis(),
+            kind: RecordKind::Remove,
+            key,
+            value: String::new(),
+            members: vec![],
+        }
+    }
+    // takes the seq of its last member
+    pub fn batch(members: Vec<Record>) -> Record {
+        Record {
+            seq: members.last().map_or(0, |member| member.seq),
+            timestamp: now_millis(),
+            kind: RecordKind::Batch,
+            key: String::new(),
+            value: String::new(),
+            members,
+        }
+    }
+    // distance from the start of the record to its value bytes
+    pub fn value_offset(&self) -> u64 {
+        HEADER_LEN + self.key.len() as u64
+    }
+    pub fn encoded_len(&self) -> u64 {
+        self.value_offset() + self.value_len()
+    }
+    fn value_len(&self) -> u64 {
+        match self.kind {
+            RecordKind::Batch => self.members.iter().map(Record::encoded_len).sum(),
+            _ => self.value.len() as u64,
+        }
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len() as usize);
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        buf.push(match self.kind {
+            RecordKind::Set => 0,
+            RecordKind::Remove => 1,
+            RecordKind::Batch => 2,
+        });
+        buf.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.value_len() as u32).to_le_bytes());
+        buf.extend_from_slice(self.key.as_bytes());
+        match self.kind {
+            RecordKind::Batch => {
+                for member in &self.members {
+                    buf.extend_from_slice(&member.encode());
+                }
+            }
+            _ => buf.extend_from_slice(self.value.as_bytes()),
+        }
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+    // read the next record, `Ok(None)` means the reader ended cleanly on a record boundary
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Option<Record>> {
+        let mut header = [0; HEADER_LEN as usize];
+        match read_full(reader, &mut header)? {
+            0 => return Ok(None),
+            n if n < header.len() => {
+                return Err(Error::CorruptedRecordErr("truncated header".to_owned()))
+            }
+            _ => {}
+        }
+        let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let seq = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        let timestamp = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        let (key_len, val_len) = body_lens(&header);
+
+        // a corrupted header may claim huge lengths, so grow the buffer with the data
+        // actually read instead of allocating up front
+        let body_len = key_len + val_len;
+        let mut body = Vec::new();
+        if reader.take(body_len as u64).read_to_end(&mut body)? < body_len {
+            return Err(Error::CorruptedRecordErr("truncated body".to_owned()));
+        }
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&body);
+        if hasher.finalize() != crc {
+            return Err(Error::CorruptedRecordErr("checksum mismatch".to_owned()));
+        }
+        // checked once the whole record is read, so a reader can go on after it
+        let kind = match header[20] {
+            0 => RecordKind::Set,
+            1 => RecordKind::Remove,
+            2 => RecordKind::Batch,
+            k => return Err(Error::CorruptedRecordErr(format!("unknown kind {}", k))),
+        };
+        let value = body.split_off(key_len);
+        let mut members = vec![];
+        let value = match kind {
+            RecordKind::Batch => {
+                let mut reader = value.as_slice();
+                while let Some(member) = Record::decode(&mut reader)? {
+                    if member.kind == RecordKind::Batch {
+                        return Err(Error::CorruptedRecordErr("nested batch".to_owned()));
+                    }
+                    members.push(member);
+                }
+                vec![]
+            }
+            _ => value,
+        };
+        let (key, value) = match (String::from_utf8(body), String::from_utf8(value)) {
+            (Ok(key), Ok(value)) => (key, value),
+            _ => return Err(Error::CorruptedRecordErr("invalid utf-8".to_owned())),
+        };
+        Ok(Some(Record {
+            seq,
+            timestamp,
+            kind,
+            key,
+            value,
+            members,
+        }))
+    }
+    // the sets and removes this record applies with their positions in the log,
+    // given its own position
+    pub fn flatten(self, position: u64) -> Vec<(u64, Record)> {
+        if self.kind != RecordKind::Batch {
+            return vec![(position, self)];
+        }
+        let mut member_position = position + self.value_offset();
+        let mut flattened = Vec::with_capacity(self.members.len());
+        for member in self.members {
+            let len = member.encoded_len();
+            flattened.push((member_position, member));
+            member_position += len;
+        }
+        flattened
+    }
+}
+
+// key and value length from an encoded header
+fn body_lens(header: &[u8]) -> (usize, usize) {
+    let key_len = u32::from_le_bytes(header[21..25].try_into().unwrap()) as usize;
+    let val_len = u32::from_le_bytes(header[25..29].try_into().unwrap()) as usize;
+    (key_len, val_len)
+}
+
+// what a record that failed to decode leaves of the log after it
+#[derive(Debug, PartialEq, Eq)]
+pub enum Damage {
+    // nothing intact follows, the remains of an interrupted append
+    TornTail,
+    // the log goes on with an intact record at `next`
+    Skip { next: u64 },
+}
+
+impl Damage {
+    // classify the record that failed at `start` after the reader went on to `end`.
+    // A record whose lengths were readable ends before the file does and the log goes on
+    // right after it. One that runs to the end of the file is a torn append unless an
+    // intact record turns up behind its start; the members of a torn batch are part of
+    // it, not records after it.
+    pub fn classify(db: &File, start: u64, end: u64) -> Result<Damage> {
+        let len = db.metadata()?.len();
+        if end < len {
+            return Ok(Damage::Skip { next: end });
+        }
+        let mut header = [0; HEADER_LEN as usize];
+        let from = match read_exact_at(db, &mut header, start) {
+            Ok(()) if header[20] == 2 => {
+                let (key_len, val_len) = body_lens(&header);
+                start + HEADER_LEN + (key_len + val_len) as u64
+            }
+            _ => start + 1,
+        };
+        if from >= len {
+            return Ok(Damage::TornTail);
+        }
+        let mut rest = vec![0; (len - from) as usize];
+        read_exact_at(db, &mut rest, from)?;
+        Ok(match find_intact(&rest) {
+            Some(offset) => Damage::Skip {
+                next: from + offset as u64,
+            },
+            None => Damage::TornTail,
+        })
+    }
+}
+
+// offset of the first record in `buf` that decodes, looking at every byte
+fn find_intact(buf: &[u8]) -> Option<usize> {
+    (0..buf.len()).find(|&offset| {
+        let rest = &buf[offset..];
+        if rest.len() < HEADER_LEN as usize || rest[20] > 2 {
+            return false;
+        }
+        let (key_len, val_len) = body_lens(rest);
+        key_len + val_len <= rest.len() - HEADER_LEN as usize
+            && matches!(Record::decode(&mut &rest[..]), Ok(Some(_)))
+    })
+}
+
+// iterates `(position, record)` pairs from the start of a log. After an error the
+// iterator is past the damaged record if its lengths were readable, see `Damage`.
+pub struct RecordIter<R: Read> {
+    reader: R,
+    position: u64,
+}
+
+impl<R: Read> RecordIter<R> {
+    // iterate a whole log file, checking its header first. Positions count from
+    // the start of the file.
+    pub fn log(mut reader: R) -> Result<RecordIter<R>> {
+        let mut magic = [0; LOG_MAGIC.len()];
+        let read = read_full(&mut reader, &mut magic)?;
+        if magic[..LOG_MAGIC.len() - 1] != LOG_MAGIC[..LOG_MAGIC.len() - 1] {
+            return Err(Error::FormatErr(if read == 0 {
+                "the log is empty, it has no header".to_owned()
+            } else {
+                "the log has no kvs header, it was written by an older version of kvs, run `kvs upgrade`".to_owned()
+            }));
+        }
+        if magic[LOG_MAGIC.len() - 1] != LOG_MAGIC[LOG_MAGIC.len() - 1] {
+            return Err(Error::FormatErr(format!(
+                "log format version {} is not supported",
+                magic[LOG_MAGIC.len() - 1]
+            )));
+        }
+        Ok(RecordIter {
+            reader,
+            position: LOG_HEADER_LEN,
+        })
+    }
+    // go on at `position` of a log, which `reader` is at, e.g. after a damaged record
+    pub fn resume(reader: R, position: u64) -> RecordIter<R> {
+        RecordIter { reader, position }
+    }
+    // where the next record starts
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<R: Read> Iterator for RecordIter<R> {
+    type Item = Result<(u64, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let position = self.position;
+        let mut reader = CountingReader {
+            inner: &mut self.reader,
+            read: 0,
+        };
+        let decoded = Record::decode(&mut reader);
+        self.position += reader.read;
+        match decoded {
+            Ok(Some(record)) => Some(Ok((position, record))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+struct CountingReader<'a, R> {
+    inner: &'a mut R,
+    read: u64,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// like `read_exact`, but reports how much was read instead of failing on a short read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[test]
+fn test_round_trip() {
+    let record = Record::set(7, "key".to_owned(), "value".to_owned());
+    let buf = record.encode();
+    assert_eq!(buf.len() as u64, record.encoded_len());
+    let decoded = Record::decode(&mut buf.as_slice()).unwrap().unwrap();
+    assert_eq!(decoded, record);
+}
+#[test]
+fn test_batch() {
+    let members = vec![
+        Record::set(1, "a".to_owned(), "1".to_owned()),
+        Record::remove(2, "b".to_owned()),
+    ];
+    let batch = Record::batch(members.clone());
+    assert_eq!(batch.seq, 2);
+    let mut buf = batch.encode();
+    assert_eq!(buf.len() as u64, batch.encoded_len());
+    let decoded = Record::decode(&mut buf.as_slice()).unwrap().unwrap();
+    assert_eq!(decoded, batch);
+
+    let flattened = decoded.flatten(100);
+    assert_eq!(flattened[0], (100 + HEADER_LEN, members[0].clone()));
+    assert_eq!(flattened[1].0, 100 + HEADER_LEN + members[0].encoded_len());
+
+    // a torn batch loses every member, not just the last
+    buf.truncate(buf.len() - 2);
+    assert!(Record::decode(&mut buf.as_slice()).is_err());
+}
+#[test]
+fn test_log_header() {
+    let mut log = LOG_MAGIC.to_vec();
+    log.extend_from_slice(&Record::set(1, "key".to_owned(), "value".to_owned()).encode());
+    let records: Vec<_> = RecordIter::log(log.as_slice()).unwrap().collect();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].as_ref().unwrap().0, LOG_HEADER_LEN);
+
+    assert!(matches!(RecordIter::log(&b"value1"[..]), Err(Error::FormatErr(_))));
+    log[LOG_MAGIC.len() - 1] = 9;
+    assert!(matches!(RecordIter::log(log.as_slice()), Err(Error::FormatErr(_))));
+}
+#[test]
+fn test_corrupted() {
+    let mut buf = Record::remove(1, "key".to_owned()).encode();
+    let last = buf.len() - 1;
+    buf[last] ^= 0xff;
+    assert!(Record::decode(&mut buf.as_slice()).is_err());
+    assert!(Record::decode(&mut &buf[..10]).is_err());
+}
+#[test]
+fn test_damage() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("log");
+    let first = Record::set(1, "a".to_owned(), "1".to_owned()).encode();
+    let second = Record::set(2, "b".to_owned(), "2".to_owned()).encode();
+    // classify the first record of `records` that fails to decode
+    let classify = |records: &[u8]| {
+        let log = [&LOG_MAGIC[..], records].concat();
+        std::fs::write(&path, &log).unwrap();
+        let db = File::open(&path).unwrap();
+        let mut records = RecordIter::log(log.as_slice()).unwrap();
+        loop {
+            let start = records.position();
+            if records.next().unwrap().is_err() {
+                return Damage::classify(&db, start, records.position()).unwrap();
+            }
+        }
+    };
+    let after = |len: usize| Damage::Skip {
+        next: LOG_HEADER_LEN + len as u64,
+    };
+
+    // a flipped byte keeps the lengths, the log goes on after the record
+    let mut log = [first.clone(), second.clone()].concat();
+    log[first.len() - 1] ^= 0xff;
+    assert_eq!(classify(&log), after(first.len()));
+    // a record cut short is a torn append
+    let log = [first.clone(), second[..10].to_vec()].concat();
+    assert_eq!(classify(&log), Damage::TornTail);
+    // a length that runs past the end of the file while intact records follow is not
+    let mut log = [first.clone(), second.clone()].concat();
+    log[25] = 0xff;
+    assert_eq!(classify(&log), after(first.len()));
+    // nor are the members of a torn batch records after it
+    let batch = Record::batch(vec![
+        Record::set(1, "a".to_owned(), "1".to_owned()),
+        Record::set(2, "b".to_owned(), "2".to_owned()),
+    ])
+    .encode();
+    assert_eq!(classify(&batch[..batch.len() - 1]), Damage::TornTail);
+}
@@ -0,0 +1,362 @@
+use crate::acl::{Access, Principal};
+use crate::engines::KvsEngine;
+use crate::error::{Error, Result};
+use crate::expire::Expiring;
+use std::{
+    io::{BufRead, Read, Write},
+    time::Duration,
+};
+
+// commands longer than this are rejected instead of buffered
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const SCAN_COUNT: usize = 10;
+
+// a reply in the redis serialization protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    // `None` is the nil bulk string
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s)?,
+            Reply::Error(s) => write!(writer, "-{}\r\n", s)?,
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Reply::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s)?,
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write_to(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// read one command, either an array of bulk strings as sent by redis clients or an
+// inline line as typed into telnet. `Ok(None)` means the connection was closed.
+pub fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<String>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if !line.starts_with('*') {
+        return Ok(Some(line.split_whitespace().map(str::to_owned).collect()));
+    }
+    let count = parse_len(&line[1..])?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(truncated)?;
+        if !header.starts_with('$') {
+            return Err(Error::ProtocolErr(format!("expected a bulk string, got {}", header)));
+        }
+        let len = parse_len(&header[1..])?;
+        let mut buf = Vec::new();
+        if reader.take(len as u64 + 2).read_to_end(&mut buf)? < len + 2 {
+            return Err(truncated());
+        }
+        buf.truncate(len);
+        args.push(String::from_utf8(buf)?);
+    }
+    Ok(Some(args))
+}
+
+// run one command against the engine
+pub fn dispatch<E: KvsEngine>(engine: &Expiring<E>, args: Vec<String>) -> Reply {
+    let mut args = args.into_iter();
+    let name = match args.next() {
+        Some(name) => name.to_ascii_uppercase(),
+        None => return Reply::Error("ERR empty command".to_owned()),
+    };
+    let args: Vec<String> = args.collect();
+    match execute(engine, &name, args) {
+        Ok(reply) => reply,
+        Err(Error::InvalidArgErr(msg)) | Err(Error::LimitExceededErr(msg)) => {
+            Reply::Error(format!("ERR {}", msg))
+        }
+        Err(e) => Reply::Error(format!("ERR {:?}", e)),
+    }
+}
+
+fn execute<E: KvsEngine>(engine: &Expiring<E>, name: &str, mut args: Vec<String>) -> Result<Reply> {
+    match (name, args.len()) {
+        ("PING", 0) => Ok(Reply::Simple("PONG".to_owned())),
+        ("PING", 1) => Ok(Reply::Bulk(args.pop())),
+        ("GET", 1) => Ok(Reply::Bulk(engine.get(args.remove(0))?)),
+        ("SET", 2) => {
+            let value = args.pop().unwrap();
+            engine.set(args.pop().unwrap(), value)?;
+            Ok(Reply::Simple("OK".to_owned()))
+        }
+        ("DEL", n) if n > 0 => {
+            let mut removed = 0;
+            for key in args {
+                match engine.remove(key) {
+                    Ok(()) => removed += 1,
+                    Err(Error::KeyNotExistErr) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Reply::Integer(removed))
+        }
+        ("EXISTS", n) if n > 0 => {
+            let mut found = 0;
+            for key in args {
+                if engine.get(key)?.is_some() {
+                    found += 1;
+                }
+            }
+            Ok(Reply::Integer(found))
+        }
+        ("KEYS", 1) => {
+            let keys = engine.keys()?.into_iter().filter(|key| glob_match(&args[0], key));
+            Ok(Reply::Array(keys.map(|key| Reply::Bulk(Some(key))).collect()))
+        }
+        ("SCAN", n) if n % 2 == 1 => scan(engine, &args),
+        ("INCR", 1) => {
+            let key = args.remove(0);
+            let n = match engine.get(key.clone())? {
+                Some(value) => value.parse::<i64>().map_err(|_| not_integer())?,
+                None => 0,
+            };
+            let n = n.checked_add(1).ok_or_else(not_integer)?;
+            engine.set(key, n.to_string())?;
+            Ok(Reply::Integer(n))
+        }
+        // a timeout that isn't positive removes the key right away
+        ("EXPIRE", 2) => {
+            let secs = args[1].parse::<i64>().map_err(|_| not_integer())?;
+            let key = args.remove(0);
+            let done = if secs > 0 {
+                engine.expire(key, Duration::from_secs(secs as u64))?
+            } else {
+                match engine.remove(key) {
+                    Ok(()) => true,
+                    Err(Error::KeyNotExistErr) => false,
+                    Err(e) => return Err(e),
+                }
+            };
+            Ok(Reply::Integer(done as i64))
+        }
+        ("PING", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _) | ("KEYS", _)
+        | ("SCAN", _) | ("INCR", _) | ("EXPIRE", _) => Err(Error::InvalidArgErr(format!(
+            "wrong number of arguments for '{}'",
+            name.to_ascii_lowercase()
+        ))),
+        _ => Err(Error::InvalidArgErr(format!(
+            "unknown command '{}'",
+            name.to_ascii_lowercase()
+        ))),
+    }
+}
+
+// the check the server runs before dispatching `args`. KEYS and SCAN need read access
+// to the literal prefix of their pattern, commands without keys other than PING need
+// admin access.
+pub fn authorize(principal: &Principal, args: &[String]) -> Result<()> {
+    let name = match args.first() {
+        Some(name) => name.to_ascii_uppercase(),
+        None => return Ok(()),
+    };
+    let mut keys = args.iter().skip(1);
+    match name.as_str() {
+        "PING" => Ok(()),
+        "GET" | "EXISTS" => keys.try_for_each(|key| principal.check(Access::Read, key)),
+        "DEL" => keys.try_for_each(|key| principal.check(Access::Write, key)),
+        "SET" | "INCR" | "EXPIRE" => {
+            keys.take(1).try_for_each(|key| principal.check(Access::Write, key))
+        }
+        "KEYS" => principal.check(Access::Read, literal_prefix(args.get(1).map_or("", |p| p))),
+        "SCAN" => {
+            // like `scan`, the last MATCH wins
+            let pattern = args
+                .get(2..)
+                .unwrap_or(&[])
+                .chunks(2)
+                .rfind(|option| option.len() == 2 && option[0].eq_ignore_ascii_case("MATCH"))
+                .map_or("*", |option| &option[1]);
+            principal.check(Access::Read, literal_prefix(pattern))
+        }
+        _ => principal.check(Access::Admin, ""),
+    }
+}
+
+// the label of a command in the server metrics, anything not served is "unknown"
+pub fn command_name(args: &[String]) -> &'static str {
+    let name = args.first().map(|name| name.to_ascii_uppercase());
+    match name.as_deref() {
+        Some("PING") => "ping",
+        Some("GET") => "get",
+        Some("SET") => "set",
+        Some("DEL") => "del",
+        Some("EXISTS") => "exists",
+        Some("KEYS") => "keys",
+        Some("SCAN") => "scan",
+        Some("INCR") => "incr",
+        Some("EXPIRE") => "expire",
+        Some("AUTH") => "auth",
+        Some("SHUTDOWN") => "shutdown",
+        Some("INFO") => "info",
+        Some("SLOWLOG") => "slowlog",
+        _ => "unknown",
+    }
+}
+
+// the part of a glob pattern before its first wildcard, every match starts with it
+fn literal_prefix(pattern: &str) -> &str {
+    &pattern[..pattern.find(&['*', '?'][..]).unwrap_or(pattern.len())]
+}
+
+// the cursor is the position in the sorted key list, so keys set during a scan may be
+// skipped or returned twice, as redis allows
+fn scan<E: KvsEngine>(engine: &E, args: &[String]) -> Result<Reply> {
+    let cursor: usize = args[0]
+        .parse()
+        .map_err(|_| Error::InvalidArgErr("invalid cursor".to_owned()))?;
+    let mut pattern = "*";
+    let mut count = SCAN_COUNT;
+    for option in args[1..].chunks(2) {
+        match option[0].to_ascii_uppercase().as_str() {
+            "MATCH" => pattern = &option[1],
+            "COUNT" => {
+                count = option[1]
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| Error::InvalidArgErr("invalid count".to_owned()))?
+            }
+            _ => return Err(Error::InvalidArgErr("syntax error".to_owned())),
+        }
+    }
+    let keys = engine.keys()?;
+    let end = cursor.saturating_add(count).min(keys.len());
+    let next = if end == keys.len() { 0 } else { end };
+    let page = keys
+        .get(cursor..end)
+        .unwrap_or(&[])
+        .iter()
+        .filter(|key| glob_match(pattern, key))
+        .map(|key| Reply::Bulk(Some(key.clone())))
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next.to_string())),
+        Reply::Array(page),
+    ]))
+}
+
+// `*` matches any run of characters and `?` any single one
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut p, mut i) = (0, 0);
+    let mut backtrack = None;
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, i));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            i = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(truncated());
+    }
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+fn parse_len(s: &str) -> Result<usize> {
+    match s.parse::<usize>() {
+        Ok(len) if len <= MAX_BULK_LEN => Ok(len),
+        _ => Err(Error::ProtocolErr(format!("invalid length {}", s))),
+    }
+}
+
+fn truncated() -> Error {
+    Error::ProtocolErr("truncated command".to_owned())
+}
+
+fn not_integer() -> Error {
+    Error::InvalidArgErr("value is not an integer or out of range".to_owned())
+}
+
+#[test]
+fn test_read_command() {
+    let mut input = "*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nva\r\nl\r\nPING\r\n".as_bytes();
+    let args = read_command(&mut input).unwrap().unwrap();
+    assert_eq!(args, vec!["SET", "key", "va\r\nl"]);
+    assert_eq!(read_command(&mut input).unwrap().unwrap(), vec!["PING"]);
+    assert!(read_command(&mut input).unwrap().is_none());
+    assert!(read_command(&mut "*1\r\n$5\r\nab\r\n".as_bytes()).is_err());
+}
+#[test]
+fn test_write_reply() {
+    let reply = Reply::Array(vec![
+        Reply::Bulk(Some("a".to_owned())),
+        Reply::Bulk(None),
+        Reply::Integer(2),
+    ]);
+    let mut buf = Vec::new();
+    reply.write_to(&mut buf).unwrap();
+    assert_eq!(buf, b"*3\r\n$1\r\na\r\n$-1\r\n:2\r\n");
+}
+#[test]
+fn test_authorize() {
+    use crate::acl::Grant;
+
+    let principal = Principal {
+        name: "app".to_owned(),
+        token: "secret".to_owned(),
+        grants: vec![Grant {
+            prefix: "app/".to_owned(),
+            rights: vec![Access::Read, Access::Write],
+        }],
+    };
+    let check = |args: &[&str]| {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        authorize(&principal, &args).is_ok()
+    };
+    assert!(check(&["PING"]));
+    assert!(check(&["get", "app/1"]));
+    assert!(check(&["SET", "app/1", "other"]));
+    assert!(!check(&["EXISTS", "app/1", "other"]));
+    assert!(!check(&["DEL", "app/1", "other"]));
+    assert!(check(&["KEYS", "app/*"]));
+    assert!(!check(&["KEYS", "app*"]));
+    assert!(!check(&["SCAN", "0"]));
+    assert!(check(&["SCAN", "0", "COUNT", "5", "MATCH", "app/?"]));
+    assert!(!check(&["SCAN", "0", "MATCH", "app/*", "MATCH", "*"]));
+    assert!(!check(&["FLUSHALL"]));
+}
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("*", "anything"));
+    assert!(glob_match("user:*", "user:1"));
+    assert!(glob_match("a?c*z", "abcxyz"));
+    assert!(!glob_match("a?c", "ac"));
+    assert!(!glob_match("user:*", "users"));
+}
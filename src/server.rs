@@ -1,5 +1,727 @@
-pub trait KvsEngine {
-    fn get(&mut self, key: String) -> String;
-    fn set(&mut self, key: String, val: String);
-    fn remove(&mut self, key: String);
-}
\ No newline at end of file
+use crate::acl::{Acl, Principal};
+use crate::engines::KvsEngine;
+use crate::error::{Error, Result};
+use crate::expire::Expiring;
+use crate::http::{self, route, HttpRequest, HttpResponse};
+use crate::limit::RateLimiter;
+use crate::metrics::{EngineGauges, Metrics};
+use crate::protocol::{
+    read_frame, write_frame, ErrorCode, Request, Response, CONNECTION_ID, MAX_FRAME_LEN,
+};
+use crate::resp::{self, read_command, Reply};
+use crate::slowlog::{SlowEntry, SlowLog};
+use crate::thread_pool::ThreadPool;
+use crate::utils::DeferDrop;
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, BufWriter, Read, Take, Write},
+    mem,
+    net::{SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info, info_span, warn};
+
+// how long the accept loop sleeps when no listener has a connection waiting
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+// how often an idle connection checks whether the server is shutting down
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+// entries SLOWLOG GET returns when not given a count
+const DEFAULT_SLOWLOG_COUNT: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    // the length-prefixed frames of `protocol`, spoken by `KvsClient`
+    Kvs,
+    // the redis serialization protocol, for redis-cli and redis client libraries
+    Resp,
+}
+
+impl FromStr for Protocol {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Protocol> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(Error::InvalidArgErr(format!("unknown protocol {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+    // where to serve the REST gateway of `http`, if anywhere
+    pub http_addr: Option<SocketAddr>,
+    // when set, clients must authenticate and may only touch what their grants allow
+    pub acl: Option<Acl>,
+    // how long a shutdown waits for open connections to finish their requests
+    pub shutdown_timeout: Duration,
+    // connections past this are turned away with a busy error
+    pub max_connections: usize,
+    // how long a connection may wait between requests, and how long reading one
+    // request or writing one response may take. `None` waits forever.
+    pub idle_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    // a larger request is answered with an error and its connection closed
+    pub max_request_size: u64,
+    // requests and request bytes a second each client may send, a client being its
+    // principal once authenticated and its ip address before
+    pub rate_limit_requests: Option<u64>,
+    pub rate_limit_bytes: Option<u64>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            addr: SocketAddr::from(([127, 0, 0, 1], 4000)),
+            protocol: Protocol::Kvs,
+            http_addr: None,
+            acl: None,
+            shutdown_timeout: Duration::from_secs(10),
+            max_connections: 1024,
+            idle_timeout: Some(Duration::from_secs(300)),
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            max_request_size: MAX_FRAME_LEN as u64,
+            rate_limit_requests: None,
+            rate_limit_bytes: None,
+        }
+    }
+}
+
+// serves one engine over tcp, each connection runs as one job on the pool. Every
+// protocol sees the same keys and deadlines.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: Expiring<E>,
+    shared: Arc<Shared>,
+    pool: P,
+}
+
+// what every connection reads
+struct Shared {
+    config: ServerConfig,
+    shutdown: Arc<AtomicBool>,
+    // accepted and not yet closed, including those still queued for a worker
+    connections: AtomicUsize,
+    limiter: Option<RateLimiter>,
+    metrics: Option<Arc<Metrics>>,
+    slowlog: Option<Arc<SlowLog>>,
+    // open sessions of each client, its limits are forgotten once the last one ends
+    clients: Mutex<HashMap<String, usize>>,
+}
+
+impl Shared {
+    fn join(&self, client: &str) {
+        *self.clients.lock().unwrap().entry(client.to_owned()).or_insert(0) += 1;
+    }
+    fn leave(&self, client: &str) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(sessions) = clients.get_mut(client) {
+            *sessions -= 1;
+            if *sessions == 0 {
+                clients.remove(client);
+                if let Some(limiter) = &self.limiter {
+                    limiter.remove(client);
+                }
+            }
+        }
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    pub fn new(engine: Expiring<E>, pool: P, config: ServerConfig) -> KvsServer<E, P> {
+        let limiter = match (config.rate_limit_requests, config.rate_limit_bytes) {
+            (None, None) => None,
+            (requests, bytes) => Some(RateLimiter::new(
+                requests.map(|n| n as f64),
+                bytes.map(|n| n as f64),
+            )),
+        };
+        KvsServer {
+            engine,
+            shared: Arc::new(Shared {
+                config,
+                shutdown: Arc::new(AtomicBool::new(false)),
+                connections: AtomicUsize::new(0),
+                limiter,
+                metrics: None,
+                slowlog: None,
+                clients: Mutex::new(HashMap::new()),
+            }),
+            pool,
+        }
+    }
+    // count requests and connections into `metrics`, which the http listener serves on
+    // `/metrics` along with the statistics of the engine
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> KvsServer<E, P> {
+        // nothing else holds `shared` before the server runs
+        Arc::get_mut(&mut self.shared).unwrap().metrics = Some(metrics);
+        self
+    }
+    // record requests slower than the threshold of `slowlog`, which admins read with
+    // SLOWLOG
+    pub fn with_slowlog(mut self, slowlog: Arc<SlowLog>) -> KvsServer<E, P> {
+        Arc::get_mut(&mut self.shared).unwrap().slowlog = Some(slowlog);
+        self
+    }
+    // setting the flag shuts the server down, e.g. from a signal handler
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.shared.shutdown)
+    }
+    // accept connections until the shutdown flag is set. The listeners are polled, which
+    // lets one thread serve all of them and notice the flag. On shutdown the listeners
+    // close, open connections answer what they have already received and close, and
+    // once they are gone or the shutdown timeout has passed the engine is flushed.
+    pub fn run(self) -> Result<()> {
+        let config = &self.shared.config;
+        let service = match config.protocol {
+            Protocol::Kvs => Service::Kvs,
+            Protocol::Resp => Service::Resp,
+        };
+        let mut listeners = vec![(bind(config.addr)?, service)];
+        if let Some(addr) = config.http_addr {
+            listeners.push((bind(addr)?, Service::Http));
+        }
+        while !self.shared.shutdown.load(Ordering::SeqCst) {
+            let mut idle = true;
+            for (listener, service) in &listeners {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        idle = false;
+                        stream.set_nonblocking(false)?;
+                        self.spawn(*service, stream, peer);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => error!("failed to accept a connection: {}", e),
+                }
+            }
+            if idle {
+                thread::sleep(ACCEPT_POLL);
+            }
+        }
+        drop(listeners);
+        info!("shutting down");
+        let deadline = Instant::now() + config.shutdown_timeout;
+        while self.shared.connections.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(ACCEPT_POLL);
+        }
+        self.engine.flush()?;
+        let open = self.shared.connections.load(Ordering::SeqCst);
+        if open > 0 {
+            // joining the workers would wait for those connections
+            warn!("{} connections still open after the shutdown timeout", open);
+            mem::forget(self.pool);
+        }
+        info!("shut down");
+        Ok(())
+    }
+    // hand the connection to the pool, or turn it away if the server is full or the
+    // pool has no room to queue it
+    fn spawn(&self, service: Service, stream: TcpStream, peer: SocketAddr) {
+        let config = &self.shared.config;
+        if self.shared.connections.load(Ordering::SeqCst) >= config.max_connections {
+            warn!(%peer, "turned away, {} connections are open", config.max_connections);
+            reject(service, &stream, "too many connections");
+            return;
+        }
+        let rejected = match stream.try_clone() {
+            Ok(rejected) => rejected,
+            Err(e) => {
+                error!(%peer, "failed to set up a connection: {}", e);
+                return;
+            }
+        };
+        let engine = self.engine.clone();
+        let shared = Arc::clone(&self.shared);
+        self.shared.connections.fetch_add(1, Ordering::SeqCst);
+        // the count drops with the job, whether it runs or the pool turns it away
+        let counted = Arc::clone(&self.shared);
+        let closed = DeferDrop::new(move || {
+            counted.connections.fetch_sub(1, Ordering::SeqCst);
+        });
+        let spawned = self.pool.try_spawn(move || {
+            let _closed = closed;
+            // everything logged while serving the connection carries its peer
+            let _span = info_span!("connection", %peer, ?service).entered();
+            let mut session = Session::new(engine, &shared, peer);
+            let served = match stream.set_write_timeout(shared.config.write_timeout) {
+                Err(e) => Err(Error::IoErr(e)),
+                Ok(()) => match service {
+                    Service::Kvs => serve(&mut session, stream),
+                    Service::Resp => serve_resp(&mut session, stream),
+                    Service::Http => serve_http(&mut session, stream),
+                },
+            };
+            if let Err(e) = served {
+                error!(%peer, "connection failed: {:?}", e);
+            }
+        });
+        if spawned.is_err() {
+            warn!(%peer, "turned away, the thread pool is busy");
+            reject(service, &rejected, "the server is busy");
+        }
+    }
+}
+
+// answer a connection that won't be served with a busy error. The write is short and
+// best effort, a client that doesn't read it just misses it.
+fn reject(service: Service, stream: &TcpStream, message: &str) {
+    let _ = stream.set_write_timeout(Some(SHUTDOWN_POLL));
+    let mut writer = BufWriter::new(stream);
+    let _ = match service {
+        Service::Kvs => write_frame(
+            &mut writer,
+            CONNECTION_ID,
+            &Response::Err {
+                code: ErrorCode::Busy,
+                message: message.to_owned(),
+            },
+        ),
+        Service::Resp => Reply::Error(format!("ERR {}", message)).write_to(&mut writer),
+        Service::Http => HttpResponse::error(503, message).write_to(&mut writer),
+    };
+    let _ = writer.flush();
+}
+
+// a request as the metrics and the slow log record it
+struct Op {
+    command: &'static str,
+    key: String,
+    value_len: usize,
+    started: Instant,
+}
+
+impl Op {
+    fn new(command: &'static str, key: Option<&str>, value_len: usize) -> Op {
+        Op {
+            command,
+            key: key.unwrap_or("").to_owned(),
+            value_len,
+            started: Instant::now(),
+        }
+    }
+    fn frame(request: &Request) -> Op {
+        let command = request.command();
+        match request {
+            Request::Get { key } | Request::Remove { key } => Op::new(command, Some(key), 0),
+            Request::Set { key, value } => Op::new(command, Some(key), value.len()),
+            _ => Op::new(command, None, 0),
+        }
+    }
+    // the first key of commands on keys, a token given to AUTH isn't one
+    fn resp(args: &[String]) -> Op {
+        let command = resp::command_name(args);
+        let key = match command {
+            "get" | "set" | "del" | "exists" | "incr" | "expire" => args.get(1),
+            _ => None,
+        };
+        let value_len = match command {
+            "set" => args.get(2).map_or(0, |value| value.len()),
+            _ => 0,
+        };
+        Op::new(command, key.map(String::as_str), value_len)
+    }
+    fn http(request: &HttpRequest) -> Op {
+        let key = http::request_key(request);
+        Op::new(http::command_name(request), key.as_deref(), request.body.len())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Service {
+    Kvs,
+    Resp,
+    Http,
+}
+
+// one connection, or one http request
+struct Session<'a, E: KvsEngine> {
+    engine: Expiring<E>,
+    shared: &'a Shared,
+    // who presented a token, checked against the acl if there is one
+    principal: Option<&'a Principal>,
+    peer: SocketAddr,
+    // whose rate limits the requests count against
+    client: String,
+}
+
+impl<'a, E: KvsEngine> Session<'a, E> {
+    fn new(engine: Expiring<E>, shared: &'a Shared, peer: SocketAddr) -> Session<'a, E> {
+        let client = peer.ip().to_string();
+        shared.join(&client);
+        if let Some(metrics) = &shared.metrics {
+            metrics.connection_opened();
+        }
+        Session {
+            engine,
+            shared,
+            principal: None,
+            peer,
+            client,
+        }
+    }
+    // without an acl any token is accepted and nothing is checked
+    fn authenticate(&mut self, token: &str) -> Result<()> {
+        if let Some(acl) = &self.shared.config.acl {
+            let principal = acl.authenticate(token)?;
+            self.principal = Some(principal);
+            self.identify(principal.name.clone());
+        }
+        Ok(())
+    }
+    fn identify(&mut self, client: String) {
+        if client != self.client {
+            self.shared.join(&client);
+            self.shared.leave(&mem::replace(&mut self.client, client));
+        }
+    }
+    fn observe(&self, op: Op, failed: bool) {
+        let elapsed = op.started.elapsed();
+        debug!(?elapsed, failed, "served");
+        if let Some(metrics) = &self.shared.metrics {
+            metrics.observe_request(op.command, elapsed, failed);
+        }
+        if let Some(slowlog) = &self.shared.slowlog {
+            let client = self.peer.to_string();
+            if let Err(e) = slowlog.record(op.command, &op.key, op.value_len, elapsed, &client) {
+                warn!("failed to write the slow log: {:?}", e);
+            }
+        }
+    }
+    // the most recent `count` slow requests, newest first
+    fn slow_requests(&self, count: usize) -> Vec<SlowEntry> {
+        self.shared.slowlog.as_ref().map_or_else(Vec::new, |slowlog| slowlog.latest(count))
+    }
+    // count a request of `bytes` against the rate limits of the client
+    fn limit(&self, bytes: u64) -> Result<()> {
+        match &self.shared.limiter {
+            Some(limiter) => limiter.check(&self.client, bytes),
+            None => Ok(()),
+        }
+    }
+    // wait until the next request starts to arrive, false once the server shuts down or
+    // the connection was idle too long. Requests already read into `buffered` are
+    // answered first.
+    fn next_request(&self, stream: &TcpStream, buffered: &[u8]) -> Result<bool> {
+        if !buffered.is_empty() {
+            return Ok(true);
+        }
+        let config = &self.shared.config;
+        let idle_since = Instant::now();
+        stream.set_read_timeout(Some(SHUTDOWN_POLL))?;
+        let ready = loop {
+            if self.shared.shutdown.load(Ordering::SeqCst) {
+                break false;
+            }
+            if config.idle_timeout.is_some_and(|timeout| idle_since.elapsed() >= timeout) {
+                info!("closing an idle connection");
+                break false;
+            }
+            // a closed connection reads as ready, the request then reads as none
+            match stream.peek(&mut [0]) {
+                Ok(_) => break true,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(Error::IoErr(e)),
+            }
+        };
+        stream.set_read_timeout(config.read_timeout)?;
+        Ok(ready)
+    }
+    // read one request with `read` along with its size in bytes, failing with
+    // `LimitExceededErr` if it is larger than `max_request_size`
+    fn read_request<R: BufRead, T>(
+        &self,
+        reader: &mut R,
+        read: impl FnOnce(&mut Take<&mut R>) -> Result<Option<T>>,
+    ) -> Result<Option<(T, u64)>> {
+        let max = self.shared.config.max_request_size;
+        let mut limited = Read::take(reader, max);
+        match read(&mut limited) {
+            Err(_) if limited.limit() == 0 => Err(Error::LimitExceededErr(format!(
+                "a request may not exceed {} bytes",
+                max
+            ))),
+            result => Ok(result?.map(|request| (request, max - limited.limit()))),
+        }
+    }
+    // the engine statistics, just the key count for engines that keep none, and the
+    // open connections
+    fn info(&self) -> Result<String> {
+        let mut info = match self.engine.stats()? {
+            Some(stats) => stats.to_string(),
+            None => format!("keys: {}\n", self.engine.keys()?.len()),
+        };
+        let connections = self.shared.connections.load(Ordering::SeqCst);
+        info.push_str(&format!("connections: {}\n", connections));
+        Ok(info)
+    }
+    fn shutdown(&self) {
+        info!("shutdown requested");
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+    }
+    fn authorize(&self, check: impl FnOnce(&Principal) -> Result<()>) -> Result<()> {
+        match (&self.shared.config.acl, self.principal) {
+            (None, _) => Ok(()),
+            (Some(_), None) => Err(Error::AuthErr("authenticate first".to_owned())),
+            (Some(_), Some(principal)) => check(principal),
+        }
+    }
+}
+
+impl<'a, E: KvsEngine> Drop for Session<'a, E> {
+    fn drop(&mut self) {
+        self.shared.leave(&self.client);
+        if let Some(metrics) = &self.shared.metrics {
+            metrics.connection_closed();
+        }
+    }
+}
+
+fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    info!("listening on {}", listener.local_addr()?);
+    Ok(listener)
+}
+
+// answer the requests of one connection in order. Responses are flushed once no
+// further request is buffered, so a pipelining client gets them in batches.
+fn serve<E: KvsEngine>(session: &mut Session<E>, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    while session.next_request(&stream, reader.buffer())? {
+        let read = session.read_request(&mut reader, |r| read_frame::<_, Request>(r));
+        let ((id, request), size) = match read {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e @ Error::LimitExceededErr(_)) => {
+                write_frame(&mut writer, CONNECTION_ID, &Response::from(Err(e)))?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let op = Op::frame(&request);
+        let _span = info_span!("request", id, command = op.command).entered();
+        let response = match session.limit(size) {
+            Ok(()) => handle(session, request),
+            Err(e) => Response::from(Err(e)),
+        };
+        session.observe(op, matches!(response, Response::Err { .. }));
+        write_frame(&mut writer, id, &response)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// clients authenticate with `AUTH token`, an admin stops the server with `SHUTDOWN`
+fn serve_resp<E: KvsEngine>(session: &mut Session<E>, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    while session.next_request(&stream, reader.buffer())? {
+        let (args, size) = match session.read_request(&mut reader, |r| read_command(r)) {
+            Ok(Some(command)) => command,
+            Ok(None) => break,
+            Err(Error::LimitExceededErr(msg)) => {
+                Reply::Error(format!("ERR {}", msg)).write_to(&mut writer)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let op = Op::resp(&args);
+        let _span = info_span!("request", command = op.command).entered();
+        let reply = match session.limit(size) {
+            Ok(()) => handle_resp(session, args),
+            Err(e) => Reply::Error(format!("ERR {}", error_message(e))),
+        };
+        session.observe(op, matches!(reply, Reply::Error(_)));
+        reply.write_to(&mut writer)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn handle_resp<E: KvsEngine>(session: &mut Session<E>, args: Vec<String>) -> Reply {
+    match args.first() {
+        Some(name) if name.eq_ignore_ascii_case("AUTH") => match args.as_slice() {
+            [_, token] => match session.authenticate(token) {
+                Ok(()) => Reply::Simple("OK".to_owned()),
+                Err(_) => Reply::Error("WRONGPASS invalid token".to_owned()),
+            },
+            _ => Reply::Error("ERR wrong number of arguments for 'auth'".to_owned()),
+        },
+        _ => match session.authorize(|principal| resp::authorize(principal, &args)) {
+            Ok(()) if args[0].eq_ignore_ascii_case("SHUTDOWN") => {
+                session.shutdown();
+                Reply::Simple("OK".to_owned())
+            }
+            Ok(()) if args[0].eq_ignore_ascii_case("INFO") => match session.info() {
+                Ok(info) => Reply::Bulk(Some(info)),
+                Err(e) => Reply::Error(format!("ERR {:?}", e)),
+            },
+            Ok(()) if args[0].eq_ignore_ascii_case("SLOWLOG") => slowlog_reply(session, &args[1..]),
+            Ok(()) => resp::dispatch(&session.engine, args),
+            Err(Error::AuthErr(_)) => Reply::Error("NOAUTH authentication required".to_owned()),
+            Err(e) => Reply::Error(format!("NOPERM {}", error_message(e))),
+        },
+    }
+}
+
+// SLOWLOG GET [count], SLOWLOG LEN and SLOWLOG RESET. GET replies with an array
+// of [id, unix time, microseconds, [command, key], client] per entry, newest first.
+fn slowlog_reply<E: KvsEngine>(session: &Session<E>, args: &[String]) -> Reply {
+    let sub = args.first().map(|sub| sub.to_ascii_uppercase());
+    match (sub.as_deref(), args.len()) {
+        (Some("GET"), 1) | (Some("GET"), 2) => {
+            let count = match args.get(1).map(|count| count.parse::<usize>()) {
+                None => DEFAULT_SLOWLOG_COUNT,
+                Some(Ok(count)) => count,
+                Some(Err(_)) => return Reply::Error("ERR invalid count".to_owned()),
+            };
+            let entries = session.slow_requests(count).into_iter().map(|entry| {
+                Reply::Array(vec![
+                    Reply::Integer(entry.id as i64),
+                    Reply::Integer((entry.timestamp / 1000) as i64),
+                    Reply::Integer(entry.duration_us as i64),
+                    Reply::Array(vec![
+                        Reply::Bulk(Some(entry.command)),
+                        Reply::Bulk(Some(entry.key)),
+                    ]),
+                    Reply::Bulk(Some(entry.client)),
+                ])
+            });
+            Reply::Array(entries.collect())
+        }
+        (Some("LEN"), 1) => {
+            let len = session.shared.slowlog.as_ref().map_or(0, |slowlog| slowlog.len());
+            Reply::Integer(len as i64)
+        }
+        (Some("RESET"), 1) => {
+            if let Some(slowlog) = &session.shared.slowlog {
+                slowlog.reset();
+            }
+            Reply::Simple("OK".to_owned())
+        }
+        _ => Reply::Error("ERR wrong arguments for 'slowlog'".to_owned()),
+    }
+}
+
+// the REST gateway, one request after the other on a kept-alive connection. Each
+// request carries its own bearer token.
+fn serve_http<E: KvsEngine>(session: &mut Session<E>, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    while session.next_request(&stream, reader.buffer())? {
+        let read = session.read_request(&mut reader, |r| HttpRequest::read_from(r));
+        let (request, size) = match read {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(Error::LimitExceededErr(msg)) => {
+                HttpResponse::error(413, &msg).write_to(&mut writer)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let op = Op::http(&request);
+        let _span = info_span!("request", command = op.command).entered();
+        session.principal = None;
+        session.identify(session.peer.ip().to_string());
+        let authorized = match &request.token {
+            Some(token) => session.authenticate(token),
+            None => Ok(()),
+        };
+        let response = match authorized
+            .and_then(|()| session.limit(size))
+            .and_then(|()| session.authorize(|principal| http::authorize(principal, &request)))
+        {
+            Ok(()) => match &session.shared.metrics {
+                Some(metrics) if op.command == "http_metrics" => {
+                    render_metrics(&session.engine, metrics)
+                }
+                _ => route(&session.engine, &request),
+            },
+            Err(Error::AuthErr(msg)) => HttpResponse::error(401, &msg),
+            Err(Error::LimitExceededErr(msg)) => HttpResponse::error(429, &msg),
+            Err(e) => HttpResponse::error(403, &error_message(e)),
+        };
+        session.observe(op, response.status >= 400);
+        response.write_to(&mut writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// the metrics with the engine gauges brought up to date
+fn render_metrics<E: KvsEngine>(engine: &E, metrics: &Metrics) -> HttpResponse {
+    match engine.stats() {
+        Ok(stats) => {
+            if let Some(stats) = stats {
+                metrics.set_engine_gauges(EngineGauges::from(&stats));
+            }
+            HttpResponse::metrics(metrics)
+        }
+        Err(e) => HttpResponse::error(500, &format!("{:?}", e)),
+    }
+}
+
+fn handle<E: KvsEngine>(session: &mut Session<E>, request: Request) -> Response {
+    let result = match request {
+        Request::Auth { token } => session.authenticate(&token).map(|()| None),
+        Request::Shutdown => session
+            .authorize(|principal| principal.authorize(&Request::Shutdown))
+            .map(|()| {
+                session.shutdown();
+                None
+            }),
+        Request::Info => session
+            .authorize(|principal| principal.authorize(&Request::Info))
+            .and_then(|()| session.info().map(Some)),
+        Request::SlowLog { count } => session
+            .authorize(|principal| principal.authorize(&Request::SlowLog { count }))
+            .and_then(|()| {
+                let mut lines = String::new();
+                for entry in session.slow_requests(count as usize) {
+                    lines.push_str(&serde_json::to_string(&entry)?);
+                    lines.push('\n');
+                }
+                Ok(Some(lines))
+            }),
+        request => session
+            .authorize(|principal| principal.authorize(&request))
+            .and_then(|()| execute(&session.engine, request)),
+    };
+    Response::from(result)
+}
+
+fn execute<E: KvsEngine>(engine: &E, request: Request) -> Result<Option<String>> {
+    match request {
+        Request::Get { key } => engine.get(key),
+        Request::Set { key, value } => engine.set(key, value).map(|()| None),
+        Request::Remove { key } => engine.remove(key).map(|()| None),
+        // the session answers these itself
+        Request::Auth { .. } | Request::Shutdown | Request::Info | Request::SlowLog { .. } => {
+            unreachable!()
+        }
+    }
+}
+
+fn error_message(err: Error) -> String {
+    match err {
+        Error::AuthErr(msg) | Error::PermissionDeniedErr(msg) | Error::LimitExceededErr(msg) => msg,
+        e => format!("{:?}", e),
+    }
+}
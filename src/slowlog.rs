@@ -0,0 +1,137 @@
+use crate::error::Result;
+use crate::record;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SlowEntry {
+    // increases by one per recorded entry, so entries dropped from the ring show as gaps
+    pub id: u64,
+    // milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub command: String,
+    pub key: String,
+    pub value_len: usize,
+    pub duration_us: u64,
+    pub client: String,
+}
+
+// operations slower than a threshold, the most recent `capacity` kept in memory and
+// optionally appended to a file as json lines
+pub struct SlowLog {
+    threshold: Duration,
+    capacity: usize,
+    inner: Mutex<SlowLogInner>,
+}
+
+struct SlowLogInner {
+    next_id: u64,
+    entries: VecDeque<SlowEntry>,
+    file: Option<File>,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, capacity: usize) -> SlowLog {
+        SlowLog {
+            threshold,
+            capacity,
+            inner: Mutex::new(SlowLogInner {
+                next_id: 0,
+                entries: VecDeque::with_capacity(capacity),
+                file: None,
+            }),
+        }
+    }
+    pub fn with_file(threshold: Duration, capacity: usize, path: &Path) -> Result<SlowLog> {
+        let slowlog = SlowLog::new(threshold, capacity);
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        slowlog.inner.lock().unwrap().file = Some(file);
+        Ok(slowlog)
+    }
+    // keep the operation if it took longer than the threshold, returns whether it did
+    pub fn record(
+        &self,
+        command: &str,
+        key: &str,
+        value_len: usize,
+        duration: Duration,
+        client: &str,
+    ) -> Result<bool> {
+        if duration <= self.threshold {
+            return Ok(false);
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let entry = SlowEntry {
+            id: inner.next_id,
+            timestamp: record::now_millis(),
+            command: command.to_owned(),
+            key: key.to_owned(),
+            value_len,
+            duration_us: duration.as_micros() as u64,
+            client: client.to_owned(),
+        };
+        inner.next_id += 1;
+        if let Some(file) = &mut inner.file {
+            let mut line = serde_json::to_vec(&entry)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+        if self.capacity > 0 {
+            if inner.entries.len() == self.capacity {
+                inner.entries.pop_front();
+            }
+            inner.entries.push_back(entry);
+        }
+        Ok(true)
+    }
+    // up to `n` entries, newest first, as SLOWLOG GET returns them
+    pub fn latest(&self, n: usize) -> Vec<SlowEntry> {
+        let inner = self.inner.lock().unwrap();
+        inner.entries.iter().rev().take(n).cloned().collect()
+    }
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn reset(&self) {
+        self.inner.lock().unwrap().entries.clear();
+    }
+}
+
+#[test]
+fn test_slowlog() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("slow.log");
+    let slowlog = SlowLog::with_file(Duration::from_millis(10), 2, &path).unwrap();
+    let fast = Duration::from_millis(5);
+    let slow = Duration::from_millis(20);
+    assert!(!slowlog.record("get", "a", 0, fast, "127.0.0.1:1").unwrap());
+    for key in &["a", "b", "c"] {
+        assert!(slowlog.record("set", key, 3, slow, "127.0.0.1:1").unwrap());
+    }
+
+    let latest = slowlog.latest(10);
+    assert_eq!(latest.len(), 2);
+    assert_eq!((latest[0].id, latest[0].key.as_str()), (2, "c"));
+    assert_eq!((latest[1].id, latest[1].key.as_str()), (1, "b"));
+    assert_eq!(latest[0].duration_us, 20_000);
+    assert_eq!(slowlog.latest(1).len(), 1);
+
+    // the file keeps everything the ring dropped
+    let logged = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(logged.lines().count(), 3);
+    let first: SlowEntry = serde_json::from_str(logged.lines().next().unwrap()).unwrap();
+    assert_eq!(first.key, "a");
+
+    slowlog.reset();
+    assert!(slowlog.is_empty());
+}
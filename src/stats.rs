@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CompactionRun {
+    // milliseconds since the unix epoch, when the run finished
+    pub timestamp: u64,
+    pub duration_ms: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub keys: u64,
+    // bytes of db.db holding the records of live keys
+    pub live_bytes: u64,
+    // overwritten and removed records, reclaimed by the next compaction
+    pub dead_bytes: u64,
+    // db files open for reading, more than one only while a compaction swaps them
+    pub segments: u64,
+    pub compactions: u64,
+    // the most recent runs, oldest first
+    pub recent_compactions: Vec<CompactionRun>,
+    // a rough estimate of the heap used by the in-memory index
+    pub index_bytes: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "keys: {}", self.keys)?;
+        writeln!(f, "live bytes: {}", self.live_bytes)?;
+        writeln!(f, "dead bytes: {}", self.dead_bytes)?;
+        writeln!(f, "segments: {}", self.segments)?;
+        writeln!(f, "index bytes: {}", self.index_bytes)?;
+        writeln!(f, "compactions: {}", self.compactions)?;
+        for run in &self.recent_compactions {
+            writeln!(
+                f,
+                "  at {}: {} -> {} bytes in {} ms",
+                run.timestamp, run.bytes_before, run.bytes_after, run.duration_ms
+            )?;
+        }
+        Ok(())
+    }
+}
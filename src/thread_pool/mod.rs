@@ -0,0 +1,28 @@
+use crate::error::Result;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub trait ThreadPool {
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+    // run `job` on the pool. A panicking job must not take the pool down with it.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+    // run `job` unless the pool has no room to queue it, in which case it fails with
+    // `BusyErr` and drops the job. Pools without a bounded queue never turn a job away.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        Ok(())
+    }
+}
@@ -0,0 +1,27 @@
+use super::ThreadPool;
+use crate::error::{Error, Result};
+use tracing::error;
+
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<RayonThreadPool> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .thread_name(|i| format!("kvs-rayon-{}", i))
+            // without a handler rayon aborts the process when a spawned job panics
+            .panic_handler(|_| error!("a job panicked in the rayon pool"))
+            .build()
+            .map_err(|e| Error::ThreadPoolErr(e.to_string()))?;
+        Ok(RayonThreadPool { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job);
+    }
+}
@@ -0,0 +1,123 @@
+use super::ThreadPool;
+use crate::error::{Error, Result};
+use tracing::error;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// a fixed set of workers pulling jobs from one queue. Dropping the pool lets the
+// workers finish the queued jobs, then joins them.
+pub struct SharedQueueThreadPool {
+    sender: Option<JobSender>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+enum JobSender {
+    Unbounded(Sender<Job>),
+    Bounded(SyncSender<Job>),
+}
+
+impl SharedQueueThreadPool {
+    // a pool whose queue holds at most `capacity` jobs waiting for a worker.
+    // `spawn` blocks while the queue is full and `try_spawn` fails instead.
+    pub fn with_capacity(threads: u32, capacity: usize) -> Result<SharedQueueThreadPool> {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(capacity);
+        SharedQueueThreadPool::start(threads, JobSender::Bounded(sender), receiver)
+    }
+    fn start(
+        threads: u32,
+        sender: JobSender,
+        receiver: Receiver<Job>,
+    ) -> Result<SharedQueueThreadPool> {
+        if threads == 0 {
+            return Err(Error::ThreadPoolErr("a pool needs at least one thread".to_owned()));
+        }
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut workers = Vec::with_capacity(threads as usize);
+        for i in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            let worker = thread::Builder::new()
+                .name(format!("kvs-worker-{}", i))
+                .spawn(move || run_jobs(&receiver))?;
+            workers.push(worker);
+        }
+        Ok(SharedQueueThreadPool {
+            sender: Some(sender),
+            workers,
+        })
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<SharedQueueThreadPool> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        SharedQueueThreadPool::start(threads, JobSender::Unbounded(sender), receiver)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // workers only stop once the sender is dropped, so sending can't fail
+        match &self.sender {
+            Some(JobSender::Unbounded(sender)) => sender.send(Box::new(job)).unwrap(),
+            Some(JobSender::Bounded(sender)) => sender.send(Box::new(job)).unwrap(),
+            None => {}
+        }
+    }
+
+    // a full bounded queue turns the job away, so a server can answer with a busy error
+    // instead of piling work up
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match &self.sender {
+            Some(JobSender::Bounded(sender)) => match sender.try_send(Box::new(job)) {
+                Err(TrySendError::Full(_)) => Err(Error::BusyErr),
+                // workers only stop once the sender is dropped
+                _ => Ok(()),
+            },
+            _ => {
+                self.spawn(job);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("a thread pool worker exited abnormally");
+            }
+        }
+    }
+}
+
+fn run_jobs(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // the lock is released as soon as a job is taken off the queue
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match job {
+            Ok(job) => {
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("a job panicked in {:?}", thread::current().name());
+                }
+            }
+            Err(_) => return,
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use crate::backup;
+use crate::entry::Entry;
+use crate::error::{Error, Result};
+use crate::kv::{self, DB_NAME, INDEX_NAME};
+use crate::record::{self, Record};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+};
+
+// convert a store written before db.db had a header into the current format. Such a
+// db.db holds bare values and index.db maps each key to the position and length of its
+// value, so every live pair is read through the old index and written out as a record.
+// Returns how many keys were converted, `None` if the store needs no upgrade.
+pub fn upgrade(path: &Path) -> Result<Option<usize>> {
+    let _lock = kv::lock_dir(path)?;
+    let db_path = path.join(DB_NAME);
+    let db = match File::open(&db_path) {
+        Ok(db) => db,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut magic = Vec::with_capacity(record::LOG_MAGIC.len());
+    (&db).take(record::LOG_MAGIC.len() as u64).read_to_end(&mut magic)?;
+    if magic.is_empty() || magic == record::LOG_MAGIC {
+        return Ok(None);
+    }
+    if magic.starts_with(&record::LOG_MAGIC[..record::LOG_MAGIC.len() - 1]) {
+        return Err(Error::FormatErr(format!(
+            "log format version {} is newer than this version of kvs",
+            magic[magic.len() - 1]
+        )));
+    }
+
+    // the old index is a bare map, an empty one was written as an empty file
+    let buf = match fs::read(path.join(INDEX_NAME)) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e.into()),
+    };
+    let index: BTreeMap<String, Entry> = if buf.is_empty() {
+        BTreeMap::new()
+    } else {
+        serde_json::from_slice(&buf).map_err(|err| {
+            Error::FormatErr(format!("index.db is not an index of the old format: {}", err))
+        })?
+    };
+    let mut log = record::LOG_MAGIC.to_vec();
+    for (seq, (key, entry)) in index.iter().enumerate() {
+        let value = Entry::get_string(&db, entry)?;
+        log.extend_from_slice(&Record::set(seq as u64 + 1, key.clone(), value).encode());
+    }
+    drop(db);
+
+    // db.db is replaced in one rename. The old index.db left behind until it is
+    // removed no longer parses, so open rebuilds the index from the new log either way.
+    backup::write_file(path, DB_NAME, &log)?;
+    fs::remove_file(path.join(INDEX_NAME)).or_else(|e| match e.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    })?;
+    backup::sync_dir(path)?;
+    Ok(Some(index.len()))
+}
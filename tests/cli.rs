@@ -1,7 +1,11 @@
 use assert_cmd::prelude::*;
+use kvs::{Error, KvStore, KvsClient, Request, Result};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::process::{Child, Command, ExitStatus};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -224,6 +228,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // the engine stays locked until the process is gone
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -335,3 +341,430 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// start `kvs-server` in `dir` and give it a second to bind
+fn spawn_server(dir: &TempDir, args: &[&str]) -> Child {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+#[test]
+fn cli_thread_pools() {
+    for (pool, addr) in &[("naive", "127.0.0.1:4006"), ("rayon", "127.0.0.1:4007")] {
+        let temp_dir = TempDir::new().unwrap();
+        let args = ["--thread-pool", pool, "--threads", "2", "--addr", addr];
+        let mut child = spawn_server(&temp_dir, &args);
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", addr])
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .assert()
+            .success()
+            .stdout("value1\n");
+        child.kill().expect("server exited before killed");
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--thread-pool", "unknown", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// many requests in flight on one connection, each answered with its own result
+#[test]
+fn client_pipeline() -> Result<()> {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+    let mut client = KvsClient::connect(addr)?;
+    let sets = (0..10_000)
+        .map(|i| Request::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        })
+        .collect();
+    assert!(client.pipeline(sets)?.iter().all(|result| matches!(result, Ok(None))));
+
+    let mut requests: Vec<Request> = (0..100)
+        .map(|i| Request::Get { key: format!("key{}", i) })
+        .collect();
+    requests.push(Request::Remove { key: "missing".to_owned() });
+    requests.push(Request::Get { key: "missing".to_owned() });
+    let mut results = client.pipeline(requests)?;
+    assert!(matches!(results.pop(), Some(Ok(None))));
+    assert!(matches!(results.pop(), Some(Err(Error::KeyNotExistErr))));
+    for (i, result) in results.into_iter().enumerate() {
+        assert_eq!(result?, Some(format!("value{}", i)));
+    }
+    assert_eq!(client.get("key7".to_owned())?, Some("value7".to_owned()));
+    child.kill().expect("server exited before killed");
+    Ok(())
+}
+
+// redis clients talk to `--protocol resp`, both in arrays and inline
+#[test]
+fn cli_resp_protocol() -> Result<()> {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--protocol", "resp", "--addr", addr]);
+    let stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut send = |command: &str, lines: usize| -> Result<String> {
+        writer.write_all(command.as_bytes())?;
+        let mut reply = String::new();
+        for _ in 0..lines {
+            reader.read_line(&mut reply)?;
+        }
+        Ok(reply)
+    };
+    assert_eq!(send("*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n", 1)?, "+OK\r\n");
+    assert_eq!(send("GET key1\r\n", 2)?, "$6\r\nvalue1\r\n");
+    assert_eq!(send("EXPIRE key1 1\r\n", 1)?, ":1\r\n");
+    assert_eq!(send("EXPIRE key2 1\r\n", 1)?, ":0\r\n");
+    assert_eq!(send("INCR n\r\n", 1)?, ":1\r\n");
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(send("GET key1\r\n", 1)?, "$-1\r\n");
+    assert_eq!(send("KEYS *\r\n", 3)?, "*1\r\n$1\r\nn\r\n");
+    assert!(send("FLUSHALL\r\n", 1)?.starts_with("-ERR"));
+    assert_eq!(send("SLOWLOG RESET\r\n", 1)?, "+OK\r\n");
+    assert!(send("SLOWLOG GET many\r\n", 1)?.starts_with("-ERR"));
+    child.kill().expect("server exited before killed");
+    Ok(())
+}
+
+// send one request on its own connection and read the whole response
+fn http(addr: &str, request: &str) -> Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(request.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+// the REST gateway serves the same store as the kvs protocol
+#[test]
+fn cli_http_gateway() -> Result<()> {
+    let (addr, http_addr) = ("127.0.0.1:4011", "127.0.0.1:4012");
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr, "--http-addr", http_addr]);
+    let response = http(http_addr, "PUT /keys/key1 HTTP/1.1\r\nContent-Length: 6\r\n\r\nvalue1")?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert_eq!(KvsClient::connect(addr)?.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let response = http(http_addr, "GET /keys/key1 HTTP/1.1\r\n\r\n")?;
+    assert!(response.ends_with("{\"key\":\"key1\",\"value\":\"value1\"}"));
+    let response = http(http_addr, "GET /keys HTTP/1.1\r\n\r\n")?;
+    assert!(response.ends_with("{\"keys\":[\"key1\"],\"next\":null}"));
+    let response = http(http_addr, "GET /keys/key2 HTTP/1.1\r\n\r\n")?;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(response.ends_with("{\"error\":\"key not found\"}"));
+
+    let metrics = http(http_addr, "GET /metrics HTTP/1.1\r\n\r\n")?;
+    assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(metrics.contains("kvs_requests_total{command=\"http_set\"} 1\n"));
+    assert!(metrics.contains("kvs_request_errors_total{command=\"http_get\"} 1\n"));
+    assert!(metrics.contains("kvs_requests_total{command=\"get\"} 1\n"));
+    assert!(metrics.contains("kvs_keys 1\n"));
+    assert!(metrics.contains("kvs_fsync_duration_seconds_count "));
+    child.kill().expect("server exited before killed");
+    Ok(())
+}
+
+// with `--acl` every protocol asks for a token and checks the grants of its principal
+#[test]
+fn cli_acl() -> Result<()> {
+    let (addr, http_addr) = ("127.0.0.1:4013", "127.0.0.1:4014");
+    let temp_dir = TempDir::new().unwrap();
+    let acl = r#"{"principals": [
+        {"name": "app", "token": "app-token",
+         "grants": [{"prefix": "app/", "rights": ["read", "write"]}]},
+        {"name": "ops", "token": "ops-token", "grants": [{"prefix": "", "rights": ["admin"]}]}
+    ]}"#;
+    fs::write(temp_dir.path().join("acl.json"), acl)?;
+    let args = ["--addr", addr, "--http-addr", http_addr, "--acl", "acl.json"];
+    let mut child = spawn_server(&temp_dir, &args);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "app/1", "value1", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("AuthErr"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "app/1", "value1", "--addr", addr, "--token", "app-token"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "other", "value1", "--addr", addr, "--token", "app-token"])
+        .assert()
+        .failure()
+        .stderr(contains("PermissionDeniedErr"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "app/1", "--addr", addr, "--token", "wrong"])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "app/1", "--addr", addr])
+        .env("KVS_TOKEN", "ops-token")
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["info", "--addr", addr, "--token", "app-token"])
+        .assert()
+        .failure()
+        .stderr(contains("PermissionDeniedErr"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["info", "--addr", addr, "--token", "ops-token"])
+        .assert()
+        .success()
+        .stdout(contains("keys: 1\n").and(contains("connections: 1\n")));
+
+    let response = http(http_addr, "GET /keys/app%2F1 HTTP/1.1\r\n\r\n")?;
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    let bearer = "Authorization: Bearer app-token\r\n";
+    let response = http(http_addr, &format!("GET /keys HTTP/1.1\r\n{}\r\n", bearer))?;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    let response = http(http_addr, &format!("GET /keys/app%2F1 HTTP/1.1\r\n{}\r\n", bearer))?;
+    assert!(response.ends_with("{\"key\":\"app/1\",\"value\":\"value1\"}"));
+    let response = http(http_addr, &format!("GET /metrics HTTP/1.1\r\n{}\r\n", bearer))?;
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    let bearer = "Authorization: Bearer ops-token\r\n";
+    let response = http(http_addr, &format!("GET /metrics HTTP/1.1\r\n{}\r\n", bearer))?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    child.kill().expect("server exited before killed");
+    Ok(())
+}
+
+// wait up to five seconds for `child` to exit by itself
+fn wait_exit(child: &mut Child) -> ExitStatus {
+    for _ in 0..50 {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    child.kill().unwrap();
+    panic!("server did not exit");
+}
+
+// SIGTERM and the shutdown request both stop the server cleanly, with the index written
+#[test]
+fn cli_graceful_shutdown() -> Result<()> {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+    // an idle connection must not hold the shutdown up
+    let mut idle = KvsClient::connect(addr).unwrap();
+    idle.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(wait_exit(&mut child).success());
+    assert!(temp_dir.path().join("index.db").exists());
+    assert!(idle.get("key1".to_owned()).is_err());
+
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shutdown", "--addr", addr])
+        .assert()
+        .success();
+    assert!(wait_exit(&mut child).success());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// connections past `--max-connections` are turned away busy, oversized requests and
+// idle connections are closed
+#[test]
+fn cli_connection_limits() -> Result<()> {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().unwrap();
+    let args = [
+        "--addr",
+        addr,
+        "--max-connections",
+        "1",
+        "--max-request-size",
+        "64",
+        "--idle-timeout",
+        "1",
+    ];
+    let mut child = spawn_server(&temp_dir, &args);
+    let mut first = KvsClient::connect(addr).unwrap();
+    first.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut second = KvsClient::connect(addr).unwrap();
+    assert!(matches!(second.get("key1".to_owned()), Err(Error::BusyErr)));
+    drop(first);
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    let result = client.set("key2".to_owned(), "v".repeat(100));
+    assert!(matches!(result, Err(Error::LimitExceededErr(_))), "{:?}", result);
+    drop(client);
+    thread::sleep(Duration::from_millis(500));
+
+    let mut idle = KvsClient::connect(addr).unwrap();
+    assert_eq!(idle.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    thread::sleep(Duration::from_secs(2));
+    assert!(idle.get("key1".to_owned()).is_err());
+    child.kill().expect("server exited before killed");
+    Ok(())
+}
+
+// a client past its rate limit or a prefix past its quota gets `LimitExceededErr`
+#[test]
+fn cli_rate_limits_and_quotas() -> Result<()> {
+    let (addr, http_addr) = ("127.0.0.1:4017", "127.0.0.1:4018");
+    let temp_dir = TempDir::new().unwrap();
+    let args = [
+        "--addr",
+        addr,
+        "--http-addr",
+        http_addr,
+        "--threads",
+        "2",
+        "--rate-limit-requests",
+        "3",
+        "--quota",
+        "q/=20",
+    ];
+    let mut child = spawn_server(&temp_dir, &args);
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("q/1".to_owned(), "value1".to_owned()).unwrap();
+    let result = client.set("q/2".to_owned(), "v".repeat(30));
+    assert!(matches!(result, Err(Error::LimitExceededErr(_))), "{:?}", result);
+    assert_eq!(client.get("q/1".to_owned()).unwrap(), Some("value1".to_owned()));
+    let result = client.get("q/1".to_owned());
+    assert!(matches!(result, Err(Error::LimitExceededErr(_))), "{:?}", result);
+    // the limit is per client, not per connection
+    let response = http(http_addr, "GET /keys HTTP/1.1\r\n\r\n")?;
+    assert!(response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{}", response);
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(client.get("q/1".to_owned()).unwrap(), Some("value1".to_owned()));
+    child.kill().expect("server exited before killed");
+    Ok(())
+}
+
+// `--log-format json` writes one object per event, carrying the connection and request
+// spans it happened in
+#[test]
+fn cli_request_spans() -> Result<()> {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().unwrap();
+    let args = [
+        "--addr",
+        addr,
+        "--log-level",
+        "debug",
+        "--log-format",
+        "json",
+        "--log-file",
+        "server.log",
+    ];
+    let mut child = spawn_server(&temp_dir, &args);
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let log = fs::read_to_string(temp_dir.path().join("server.log"))?;
+    let served = log
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .find(|event| event["fields"]["message"] == "served")
+        .expect("no request was logged");
+    assert_eq!(served["spans"][0]["name"], "connection");
+    assert_eq!(served["spans"][1]["name"], "request");
+    assert!(served["spans"][1]["fields"].to_string().contains("set"));
+    Ok(())
+}
+
+// with a zero threshold every request lands in the slow log
+#[test]
+fn cli_slowlog() -> Result<()> {
+    let addr = "127.0.0.1:4020";
+    let temp_dir = TempDir::new().unwrap();
+    let args = ["--addr", addr, "--slowlog-threshold-us", "0", "--slowlog-file", "slow.log"];
+    let mut child = spawn_server(&temp_dir, &args);
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let entries = client.slowlog(10).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!((entries[0].command.as_str(), entries[0].key.as_str()), ("set", "key1"));
+    assert_eq!(entries[0].value_len, 6);
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["slowlog", "1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains(" slowlog \"\" 127.0.0.1:"));
+    child.kill().expect("server exited before killed");
+    let logged = fs::read_to_string(temp_dir.path().join("slow.log"))?;
+    assert!(logged.lines().next().unwrap().contains("\"key\":\"key1\""));
+    Ok(())
+}
+
+// `--data-dir` and KVS_DATA_DIR put the store elsewhere than the current directory,
+// creating and marking it if it is missing
+#[test]
+fn cli_server_data_dir() -> Result<()> {
+    let addr = "127.0.0.1:4021";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr, "--data-dir", "data"]);
+    KvsClient::connect(addr).unwrap().set("key1".to_owned(), "value1".to_owned()).unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    assert_eq!(fs::read_to_string(data_dir.join("engine"))?, "kvs");
+    assert!(!temp_dir.path().join("db.db").exists());
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .env("KVS_DATA_DIR", &data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let value = KvsClient::connect(addr).unwrap().get("key1".to_owned()).unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert_eq!(value, Some("value1".to_owned()));
+
+    fs::write(temp_dir.path().join("file"), "")?;
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--data-dir", "file"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("is not a directory"));
+    Ok(())
+}
@@ -1,4 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    diff, dispatch, fsck, migrate_dir, parse_batch, prepare_data_dir, read_engine_marker, route,
+    BatchMode, DataFormat, DiffSummary, Difference, Error, Expiring, FsckMode, HttpRequest,
+    ImportPolicy, ImportSummary, KvStore, KvsEngine, Metrics, Outcome, Reply, RestorePoint, Result,
+};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +15,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +25,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +36,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +45,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +57,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +73,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +81,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +93,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +123,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +133,874 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should restore every pair from a backup taken while the store is open
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.backup(backup_dir.path())?;
+
+    // writes after the backup must not show up in the restored store
+    store.set("key1".to_owned(), "changed".to_owned())?;
+
+    let restored = KvStore::restore(backup_dir.path(), restore_dir.path())?;
+    assert_eq!(restored.get("key0".to_owned())?, None);
+    for i in 1..100 {
+        assert_eq!(restored.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Should refuse to restore a backup whose files were modified
+#[test]
+fn restore_corrupted_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup(backup_dir.path())?;
+
+    fs::write(backup_dir.path().join("db.db"), "value2")?;
+    assert!(KvStore::restore(backup_dir.path(), restore_dir.path()).is_err());
+    Ok(())
+}
+
+// Restore must not touch a directory held by an open store, nor replace a store
+// unless asked to
+#[test]
+fn restore_into_existing_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let target_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "backed up".to_owned())?;
+    store.backup(backup_dir.path())?;
+    drop(store);
+
+    let live = KvStore::open(target_dir.path())?;
+    live.set("key1".to_owned(), "live".to_owned())?;
+    let db_before = fs::read(target_dir.path().join("db.db"))?;
+    assert!(matches!(
+        KvStore::restore_until(backup_dir.path(), target_dir.path(), RestorePoint::Latest, true),
+        Err(Error::LockedErr(_))
+    ));
+    assert_eq!(fs::read(target_dir.path().join("db.db"))?, db_before);
+    drop(live);
+
+    assert!(matches!(
+        KvStore::restore(backup_dir.path(), target_dir.path()),
+        Err(Error::BackupErr(_))
+    ));
+    assert_eq!(
+        KvStore::open(target_dir.path())?.get("key1".to_owned())?,
+        Some("live".to_owned())
+    );
+    let restored =
+        KvStore::restore_until(backup_dir.path(), target_dir.path(), RestorePoint::Latest, true)?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("backed up".to_owned()));
+    Ok(())
+}
+
+// Should restore a full backup plus its increments, up to the latest or an earlier seq
+#[test]
+fn incremental_backup_and_point_in_time_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // seq 1..=10
+    for i in 0..10 {
+        store.set(format!("key{}", i), "v1".to_owned())?;
+    }
+    // the first incremental backup into an empty directory is a full one
+    store.backup_incremental(backup_dir.path())?;
+    // seq 11..=20
+    for i in 0..10 {
+        store.set(format!("key{}", i), "v2".to_owned())?;
+    }
+    store.backup_incremental(backup_dir.path())?;
+    // seq 21
+    store.remove("key0".to_owned())?;
+    store.backup_incremental(backup_dir.path())?;
+    assert!(backup_dir.path().join("incr-000002").exists());
+
+    let latest_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let latest = KvStore::restore(backup_dir.path(), latest_dir.path())?;
+    assert_eq!(latest.get("key0".to_owned())?, None);
+    assert_eq!(latest.get("key9".to_owned())?, Some("v2".to_owned()));
+
+    let point_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let point = KvStore::restore_until(backup_dir.path(), point_dir.path(), RestorePoint::Seq(15), false)?;
+    assert_eq!(point.get("key0".to_owned())?, Some("v2".to_owned()));
+    assert_eq!(point.get("key4".to_owned())?, Some("v2".to_owned()));
+    assert_eq!(point.get("key5".to_owned())?, Some("v1".to_owned()));
+
+    // the full backup holds seq 10, nothing before it can be restored
+    let early_dir = TempDir::new().expect("unable to create temporary restore directory");
+    assert!(KvStore::restore_until(backup_dir.path(), early_dir.path(), RestorePoint::Seq(5), false).is_err());
+    Ok(())
+}
+
+// Compaction must keep records that have not been backed up yet
+#[test]
+fn incremental_backup_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.backup_incremental(backup_dir.path())?;
+
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    let commands = parse_batch("set key3 value3\nset key3 value4\n".as_bytes())?;
+    store.apply_batch(commands, BatchMode::Transactional)?;
+    store.compact()?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    store.backup_incremental(backup_dir.path())?;
+
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let restored = KvStore::restore(backup_dir.path(), restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, None);
+    assert_eq!(restored.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(restored.get("key3".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Full backups keep no history, compaction reclaims everything and an incremental
+// backup on top of one is refused
+#[test]
+fn full_backup_keeps_no_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.backup(backup_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!((stats.keys, stats.dead_bytes), (10, 0));
+    assert!(matches!(
+        store.backup_incremental(backup_dir.path()),
+        Err(Error::BackupErr(_))
+    ));
+    Ok(())
+}
+
+// An index.db that no longer matches the log is rebuilt instead of trusted, and a
+// log without the format header is refused
+#[test]
+fn stale_index_is_rebuilt() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "v1".to_owned())?;
+    }
+    store.flush()?;
+    let stale_index = fs::read(temp_dir.path().join("index.db"))?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "v2".to_owned())?;
+    }
+    store.set("key10".to_owned(), "v2".to_owned())?;
+    store.compact()?;
+    drop(store);
+    fs::write(temp_dir.path().join("index.db"), stale_index)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..11 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("v2".to_owned()));
+    }
+
+    let old_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(old_dir.path().join("db.db"), "value1value2")?;
+    assert!(matches!(KvStore::open(old_dir.path()), Err(Error::FormatErr(_))));
+    Ok(())
+}
+
+// fsck should notice an index.db left behind by an older session and rebuild it
+#[test]
+fn fsck_repairs_stale_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let stale_index = fs::read(temp_dir.path().join("index.db"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    fs::write(temp_dir.path().join("index.db"), stale_index)?;
+
+    let report = fsck(temp_dir.path(), FsckMode::Check)?;
+    assert!(!report.is_clean());
+    assert_eq!(report.dangling, vec!["key1".to_owned()]);
+    assert_eq!(report.orphaned, vec!["key3".to_owned()]);
+
+    assert!(fsck(temp_dir.path(), FsckMode::Repair)?.repaired);
+    assert!(fsck(temp_dir.path(), FsckMode::Check)?.is_clean());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// fsck --repair should cut a torn record off the end of the log
+#[test]
+fn fsck_truncates_corrupt_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let db_len = fs::metadata(temp_dir.path().join("db.db"))?.len();
+    let mut db = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("db.db"))?;
+    db.write_all(b"torn write")?;
+    drop(db);
+
+    let report = fsck(temp_dir.path(), FsckMode::Repair)?;
+    assert_eq!(report.corrupt_tail.map(|(position, _)| position), Some(db_len));
+    assert!(report.repaired);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// fsck should skip a damaged record inside the log and keep the records after it,
+// dropping those only when asked to truncate at the damage
+#[test]
+fn fsck_skips_damaged_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db_path = temp_dir.path().join("db.db");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first_end = fs::metadata(&db_path)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut log = fs::read(&db_path)?;
+    log[first_end as usize - 1] ^= 0xff;
+    fs::write(&db_path, &log)?;
+    let damaged = temp_dir.path().join("damaged");
+    fs::create_dir(&damaged)?;
+    fs::write(damaged.join("db.db"), &log)?;
+
+    let report = fsck(temp_dir.path(), FsckMode::Check)?;
+    assert_eq!(report.records, 2);
+    assert_eq!(report.damaged.len(), 1);
+    assert_eq!(report.damaged[0].0, 8);
+    assert_eq!(report.corrupt_tail, None);
+    assert!(!report.repaired);
+
+    assert!(fsck(temp_dir.path(), FsckMode::Repair)?.repaired);
+    assert!(fsck(temp_dir.path(), FsckMode::Check)?.is_clean());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    assert!(fsck(&damaged, FsckMode::Truncate)?.repaired);
+    assert_eq!(fs::metadata(damaged.join("db.db"))?.len(), 8);
+    let store = KvStore::open(&damaged)?;
+    assert!(store.keys().is_empty());
+    Ok(())
+}
+
+// Pairs exported in either format should import into another store unchanged
+#[test]
+fn export_and_import() -> Result<()> {
+    for format in [DataFormat::JsonLines, DataFormat::Csv] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("user:1".to_owned(), "alice".to_owned())?;
+        store.set("user:2".to_owned(), "bob, \"the\"\nbuilder".to_owned())?;
+        store.set("order:1".to_owned(), "42".to_owned())?;
+
+        let mut buf = vec![];
+        assert_eq!(store.export(&mut buf, format, "user:")?, 2);
+
+        let other_dir = TempDir::new().expect("unable to create temporary working directory");
+        let other = KvStore::open(other_dir.path())?;
+        other.set("user:1".to_owned(), "carol".to_owned())?;
+        let summary = other.import(buf.as_slice(), format, ImportPolicy::Skip)?;
+        assert_eq!(summary, ImportSummary { imported: 1, skipped: 1 });
+        assert_eq!(other.get("user:1".to_owned())?, Some("carol".to_owned()));
+        assert_eq!(other.get("user:2".to_owned())?, store.get("user:2".to_owned())?);
+        assert_eq!(other.get("order:1".to_owned())?, None);
+
+        assert!(other.import(buf.as_slice(), format, ImportPolicy::Fail).is_err());
+        other.import(buf.as_slice(), format, ImportPolicy::Overwrite)?;
+        assert_eq!(other.get("user:1".to_owned())?, Some("alice".to_owned()));
+    }
+    Ok(())
+}
+
+// Should move every pair from kvs to sled and back, marking each destination
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let back_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(kvs_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let there = migrate_dir("kvs", kvs_dir.path(), "sled", sled_dir.path())?;
+    assert_eq!(there.keys, 99);
+    assert_eq!(read_engine_marker(sled_dir.path())?, Some("sled".to_owned()));
+
+    let back = migrate_dir("sled", sled_dir.path(), "kvs", back_dir.path())?;
+    assert_eq!(back, there);
+    let store = KvStore::open(back_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+
+    // the sled directory is marked, it can't be read as a kvs store
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(migrate_dir("kvs", sled_dir.path(), "sled", other_dir.path()).is_err());
+
+    // a directory without a store is not migrated as an empty one
+    let missing = other_dir.path().join("missing");
+    assert!(matches!(
+        migrate_dir("kvs", &missing, "sled", other_dir.path()),
+        Err(Error::MigrateErr(_))
+    ));
+    assert!(!missing.exists());
+    let unrelated_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(unrelated_dir.path().join("notes.txt"), "not a store")?;
+    assert!(matches!(
+        migrate_dir("kvs", unrelated_dir.path(), "sled", other_dir.path()),
+        Err(Error::MigrateErr(_))
+    ));
+    assert_eq!(read_engine_marker(other_dir.path())?, None);
+
+    // but a store without keys is
+    let empty_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(empty_dir.path())?;
+    let summary = migrate_dir("kvs", empty_dir.path(), "sled", other_dir.path())?;
+    assert_eq!(summary.keys, 0);
+    assert_eq!(read_engine_marker(other_dir.path())?, Some("sled".to_owned()));
+    Ok(())
+}
+
+// Should report differences in both directions, in key order
+#[test]
+fn diff_stores() -> Result<()> {
+    let a_dir = TempDir::new().expect("unable to create temporary working directory");
+    let b_dir = TempDir::new().expect("unable to create temporary working directory");
+    let a = KvStore::open(a_dir.path())?;
+    let b = KvStore::open(b_dir.path())?;
+    a.set("same".to_owned(), "1".to_owned())?;
+    b.set("same".to_owned(), "1".to_owned())?;
+    a.set("changed".to_owned(), "old".to_owned())?;
+    b.set("changed".to_owned(), "new".to_owned())?;
+    a.set("only_a".to_owned(), "a".to_owned())?;
+    b.set("only_b".to_owned(), "b".to_owned())?;
+
+    let mut found = vec![];
+    let summary = diff(&a, &b, |d| {
+        found.push(d);
+        Ok(())
+    })?;
+    assert_eq!(
+        found,
+        vec![
+            Difference::Changed { key: "changed".to_owned(), old: "old".to_owned(), new: "new".to_owned() },
+            Difference::Removed { key: "only_a".to_owned(), value: "a".to_owned() },
+            Difference::Added { key: "only_b".to_owned(), value: "b".to_owned() },
+        ]
+    );
+    assert_eq!(summary, DiffSummary { added: 1, removed: 1, changed: 1, unchanged: 1 });
+    assert!(!a.compare(&b)?);
+
+    b.remove("only_b".to_owned())?;
+    b.set("changed".to_owned(), "old".to_owned())?;
+    b.set("only_a".to_owned(), "a".to_owned())?;
+    assert!(a.compare(&b)?);
+    Ok(())
+}
+
+// Readers on clones of the store should see consistent values while another clone
+// keeps writing and compacting
+#[test]
+fn concurrent_reads_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let writer = store.clone();
+    let writes = thread::spawn(move || -> Result<()> {
+        // rewrites the same pairs until the log has been compacted several times
+        for _ in 0..200 {
+            for i in 0..100 {
+                writer.set(format!("key{}", i), format!("value{}", i))?;
+            }
+        }
+        Ok(())
+    });
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let reader = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..20 {
+                    for i in 0..100 {
+                        assert_eq!(reader.get(format!("key{}", i))?, Some(format!("value{}", i)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    writes.join().unwrap()?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let engine = Expiring::open(store.clone(), temp_dir.path())?;
+    let run = |args: &[&str]| dispatch(&engine, args.iter().map(|s| s.to_string()).collect());
+
+    assert_eq!(run(&["ping"]), Reply::Simple("PONG".to_owned()));
+    assert_eq!(run(&["SET", "a", "1"]), Reply::Simple("OK".to_owned()));
+    assert_eq!(run(&["SET", "b", "x"]), Reply::Simple("OK".to_owned()));
+    assert_eq!(run(&["GET", "a"]), Reply::Bulk(Some("1".to_owned())));
+    assert_eq!(run(&["GET", "c"]), Reply::Bulk(None));
+    assert_eq!(run(&["INCR", "a"]), Reply::Integer(2));
+    assert_eq!(run(&["INCR", "c"]), Reply::Integer(1));
+    assert!(matches!(run(&["INCR", "b"]), Reply::Error(_)));
+    assert_eq!(run(&["EXISTS", "a", "b", "d"]), Reply::Integer(2));
+    assert_eq!(
+        run(&["KEYS", "*"]),
+        Reply::Array(vec![
+            Reply::Bulk(Some("a".to_owned())),
+            Reply::Bulk(Some("b".to_owned())),
+            Reply::Bulk(Some("c".to_owned())),
+        ])
+    );
+    assert_eq!(
+        run(&["SCAN", "0", "COUNT", "2"]),
+        Reply::Array(vec![
+            Reply::Bulk(Some("2".to_owned())),
+            Reply::Array(vec![Reply::Bulk(Some("a".to_owned())), Reply::Bulk(Some("b".to_owned()))]),
+        ])
+    );
+    assert_eq!(
+        run(&["SCAN", "2", "COUNT", "2"]),
+        Reply::Array(vec![
+            Reply::Bulk(Some("0".to_owned())),
+            Reply::Array(vec![Reply::Bulk(Some("c".to_owned()))]),
+        ])
+    );
+    assert_eq!(run(&["DEL", "a", "d", "c"]), Reply::Integer(2));
+    assert_eq!(run(&["EXPIRE", "b", "10"]), Reply::Integer(1));
+    assert_eq!(run(&["EXPIRE", "d", "10"]), Reply::Integer(0));
+    assert!(matches!(run(&["EXPIRE", "b", "soon"]), Reply::Error(_)));
+    assert!(matches!(run(&["GET"]), Reply::Error(_)));
+    assert!(matches!(run(&["FLUSHALL"]), Reply::Error(_)));
+
+    assert_eq!(store.keys(), vec!["b".to_owned()]);
+    Ok(())
+}
+
+#[test]
+fn expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let engine = Expiring::open(store.clone(), temp_dir.path())?;
+    for key in &["a", "b", "c"] {
+        engine.set(key.to_string(), "v".to_owned())?;
+    }
+    assert!(engine.expire("a".to_owned(), Duration::from_millis(50))?);
+    assert!(engine.expire("b".to_owned(), Duration::from_millis(50))?);
+    assert!(engine.expire("c".to_owned(), Duration::from_secs(60))?);
+    assert!(!engine.expire("d".to_owned(), Duration::from_secs(60))?);
+    // a set drops the deadline
+    engine.set("b".to_owned(), "w".to_owned())?;
+    thread::sleep(Duration::from_millis(100));
+
+    assert_eq!(engine.get("a".to_owned())?, None);
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(engine.get("b".to_owned())?, Some("w".to_owned()));
+    assert_eq!(engine.keys()?, vec!["b".to_owned(), "c".to_owned()]);
+
+    // deadlines outlive the process
+    drop(engine);
+    let engine = Expiring::open(store, temp_dir.path())?;
+    let deadlines = fs::read_to_string(temp_dir.path().join("ttl.json"))?;
+    assert!(deadlines.contains("\"c\"") && !deadlines.contains("\"b\""));
+    assert_eq!(engine.get("c".to_owned())?, Some("v".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let send = |method: &str, target: &str, body: &str| {
+        let request = HttpRequest {
+            method: method.to_owned(),
+            target: target.to_owned(),
+            body: body.to_owned(),
+            token: None,
+        };
+        let response = route(&store, &request);
+        let body: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        (response.status, body)
+    };
+
+    for key in &["a", "b", "c%2Fd", "e"] {
+        assert_eq!(send("PUT", &format!("/keys/{}", key), "v").0, 200);
+    }
+    let (status, body) = send("GET", "/keys/c%2Fd", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["key"], "c/d");
+    assert_eq!(body["value"], "v");
+
+    let (status, body) = send("GET", "/keys?limit=2", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["keys"], serde_json::json!(["a", "b"]));
+    assert_eq!(body["next"], "b");
+    let (_, body) = send("GET", "/keys?after=b&limit=2", "");
+    assert_eq!(body["keys"], serde_json::json!(["c/d", "e"]));
+    assert!(body["next"].is_null());
+
+    assert_eq!(send("DELETE", "/keys/a", "").0, 200);
+    let (status, body) = send("GET", "/keys/a", "");
+    assert_eq!(status, 404);
+    assert_eq!(body["error"], "key not found");
+    assert_eq!(send("DELETE", "/keys/a", "").0, 404);
+    assert_eq!(send("GET", "/keys?limit=0", "").0, 400);
+    assert_eq!(send("POST", "/keys/a", "").0, 405);
+    assert_eq!(send("GET", "/other", "").0, 404);
+    Ok(())
+}
+
+#[test]
+fn flush_persists_index_without_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.flush()?;
+
+    // what a process killed right now would leave behind
+    let index = fs::read_to_string(temp_dir.path().join("index.db"))?;
+    assert!(index.contains("key2") && !index.contains("key1"));
+    let copy = TempDir::new().expect("unable to create temporary working directory");
+    for name in &["db.db", "index.db"] {
+        fs::copy(temp_dir.path().join(name), copy.path().join(name))?;
+    }
+    let copied = KvStore::open(copy.path())?;
+    assert_eq!(copied.get("key1".to_owned())?, None);
+    assert_eq!(copied.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // writes after the flush leave index.db stale, open must not trust it
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let copy = TempDir::new().expect("unable to create temporary working directory");
+    for name in &["db.db", "index.db"] {
+        fs::copy(temp_dir.path().join(name), copy.path().join(name))?;
+    }
+    let copied = KvStore::open(copy.path())?;
+    assert_eq!(copied.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(copied.get("key2".to_owned())?, None);
+    assert_eq!(copied.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn prefix_quota() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("app/a".to_owned(), "12345".to_owned())?;
+    store.set("other".to_owned(), "x".repeat(100))?;
+    store.set_quota("app/", 20);
+    assert_eq!(store.quota_usage("app/"), Some((10, 20)));
+
+    store.set("app/b".to_owned(), "12345".to_owned())?;
+    assert_eq!(store.quota_usage("app/"), Some((20, 20)));
+    assert!(matches!(
+        store.set("app/c".to_owned(), "1".to_owned()),
+        Err(Error::LimitExceededErr(_))
+    ));
+    assert_eq!(store.get("app/c".to_owned())?, None);
+    // keys outside the prefix and shrinking writes are not limited
+    store.set("other".to_owned(), "x".repeat(200))?;
+    store.set("app/a".to_owned(), "1".to_owned())?;
+    assert_eq!(store.quota_usage("app/"), Some((16, 20)));
+    store.remove("app/b".to_owned())?;
+    assert_eq!(store.quota_usage("app/"), Some((6, 20)));
+
+    store.remove_quota("app/");
+    assert_eq!(store.quota_usage("app/"), None);
+    store.set("app/c".to_owned(), "x".repeat(100))?;
+    Ok(())
+}
+
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!((stats.keys, stats.live_bytes, stats.dead_bytes), (0, 0, 0));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let live_bytes = store.stats()?.live_bytes;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.live_bytes, live_bytes / 2);
+    assert!(stats.dead_bytes > live_bytes / 2);
+    assert_eq!(stats.segments, 1);
+    assert!(stats.index_bytes > 0);
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.dead_bytes, 0);
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.recent_compactions.len(), 1);
+    assert_eq!(
+        stats.recent_compactions[0].bytes_after,
+        fs::metadata(temp_dir.path().join("db.db"))?.len()
+    );
+
+    // the history outlives the process, also when the index has to be rebuilt
+    drop(store);
+    fs::remove_file(temp_dir.path().join("index.db"))?;
+    let reopened = KvStore::open(temp_dir.path())?.stats()?;
+    assert_eq!(reopened.compactions, 1);
+    assert_eq!(reopened.recent_compactions, stats.recent_compactions);
+    Ok(())
+}
+
+#[test]
+fn store_reports_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let metrics = Arc::new(Metrics::new());
+    store.set_metrics(Arc::clone(&metrics))?;
+    assert!(metrics.render().contains("kvs_keys 1\n"));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+    let out = metrics.render();
+    assert!(out.contains("kvs_fsync_duration_seconds_count 1\n"));
+    assert!(out.contains("kvs_keys 1\n"));
+    store.update_metrics()?;
+    assert!(metrics.render().contains("kvs_keys 2\n"));
+
+    store.compact()?;
+    let out = metrics.render();
+    assert!(out.contains("kvs_compaction_duration_seconds_count 1\n"));
+    assert!(out.contains("kvs_dead_bytes 0\n"));
+    Ok(())
+}
+
+#[test]
+fn data_dir_is_locked_and_marked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    prepare_data_dir(&data_dir, "kvs")?;
+    assert_eq!(read_engine_marker(&data_dir)?, Some("kvs".to_owned()));
+    assert!(matches!(prepare_data_dir(&data_dir, "sled"), Err(Error::WrongEngineErr(_))));
+
+    let store = KvStore::open(&data_dir)?;
+    assert!(matches!(KvStore::open(&data_dir), Err(Error::LockedErr(_))));
+    assert!(matches!(fsck(&data_dir, FsckMode::Check), Err(Error::LockedErr(_))));
+    // clones share the lock
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(&data_dir).is_err());
+    drop(clone);
+    KvStore::open(&data_dir)?;
+
+    let file = temp_dir.path().join("file");
+    fs::write(&file, "")?;
+    assert!(matches!(prepare_data_dir(&file, "kvs"), Err(Error::InvalidArgErr(_))));
+    Ok(())
+}
+
+#[test]
+fn batch_modes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "old".to_owned())?;
+    let script = "# setup\nset a hello world\nrm missing\nrm b\n\nget a\n";
+    let outcomes = |summary: &kvs::BatchSummary| -> Vec<Outcome> {
+        summary.results.iter().map(|(_, _, outcome)| outcome.clone()).collect()
+    };
+
+    let commands = parse_batch(script.as_bytes())?;
+    assert_eq!(commands.iter().map(|(line, _)| *line).collect::<Vec<_>>(), vec![2, 3, 4, 6]);
+    let summary = store.apply_batch(commands.clone(), BatchMode::Transactional)?;
+    assert!(!summary.is_success());
+    assert!(summary.aborted.is_some());
+    assert!(matches!(outcomes(&summary)[1], Outcome::Failed(_)));
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(store.get("b".to_owned())?, Some("old".to_owned()));
+    // a get that saw the batch's own write is skipped with it
+    let aborted = parse_batch("set x 1\nget x\nrm missing\n".as_bytes())?;
+    let summary = store.apply_batch(aborted, BatchMode::Transactional)?;
+    assert_eq!((summary.succeeded, summary.failed, summary.skipped), (0, 1, 2));
+    assert_eq!(outcomes(&summary)[1], Outcome::Skipped);
+
+    let summary = store.apply_batch(commands.clone(), BatchMode::StopOnError)?;
+    assert_eq!((summary.succeeded, summary.failed, summary.skipped), (1, 1, 2));
+    assert_eq!(store.get("b".to_owned())?, Some("old".to_owned()));
+
+    let summary = store.apply_batch(commands, BatchMode::ContinueOnError)?;
+    assert_eq!((summary.succeeded, summary.failed, summary.skipped), (3, 1, 0));
+    assert_eq!(outcomes(&summary)[3], Outcome::Value(Some("hello world".to_owned())));
+    assert_eq!(store.get("b".to_owned())?, None);
+
+    // a transaction sees its own writes and applies them all
+    let commands = parse_batch("set c 1\nrm c\nset d 2\nget c\nget d\n".as_bytes())?;
+    let summary = store.apply_batch(commands, BatchMode::Transactional)?;
+    assert!(summary.is_success());
+    assert_eq!(outcomes(&summary)[3], Outcome::Value(None));
+    assert_eq!(outcomes(&summary)[4], Outcome::Value(Some("2".to_owned())));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("c".to_owned())?, None);
+    assert_eq!(store.get("d".to_owned())?, Some("2".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("a".to_owned())?, Some("hello world".to_owned()));
+    assert_eq!(store.get("d".to_owned())?, Some("2".to_owned()));
+
+    // a quota turns the whole transaction down
+    store.set_quota("q/", 4);
+    let commands = parse_batch("set q/a 1\nset q/b 2\n".as_bytes())?;
+    let summary = store.apply_batch(commands, BatchMode::Transactional)?;
+    assert!(summary.aborted.is_some());
+    assert_eq!(summary.skipped, 2);
+    assert_eq!(store.get("q/a".to_owned())?, None);
+    assert_eq!(store.quota_usage("q/"), Some((0, 4)));
+
+    assert!(parse_batch("set a\n".as_bytes()).is_err());
+    assert!(parse_batch("put a 1\n".as_bytes()).is_err());
+    Ok(())
+}
+
+// A transactional batch torn by a crash is dropped whole by fsck --repair
+#[test]
+fn torn_batch_is_dropped_whole() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("t0".to_owned(), "old".to_owned())?;
+    let commands = parse_batch("set t1 aaaa\nset t2 bbbb\nrm t0\nset t3 cccc\n".as_bytes())?;
+    assert!(store.apply_batch(commands, BatchMode::Transactional)?.is_success());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("t0".to_owned())?, None);
+    assert_eq!(store.get("t3".to_owned())?, Some("cccc".to_owned()));
+    drop(store);
+
+    let db = OpenOptions::new().write(true).open(temp_dir.path().join("db.db"))?;
+    db.set_len(db.metadata()?.len() - 2)?;
+    drop(db);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("t0".to_owned())?, Some("old".to_owned()));
+    for key in &["t1", "t2", "t3"] {
+        assert_eq!(store.get(key.to_string())?, None);
+    }
+    Ok(())
+}
+
+// Should cut off a torn append on open, but refuse a log damaged before its end
+#[test]
+fn torn_tail_is_truncated_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db_path = temp_dir.path().join("db.db");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let len = fs::metadata(&db_path)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let db = OpenOptions::new().write(true).open(&db_path)?;
+    db.set_len(len + 5)?;
+    drop(db);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&db_path)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    // appends go on right after the last good record
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // a flipped byte in the first record is not a torn append
+    let mut log = fs::read(&db_path)?;
+    log[len as usize - 1] ^= 0xff;
+    fs::write(&db_path, &log)?;
+    fs::remove_file(temp_dir.path().join("index.db"))?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(Error::CorruptedRecordErr(_))
+    ));
+    assert_eq!(fs::read(&db_path)?, log);
+    Ok(())
+}
+
+// Should convert a store of bare values written before the log had a header
+#[test]
+fn upgrade_old_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // key1 was overwritten, its first value is dead
+    fs::write(temp_dir.path().join("db.db"), "oldvalue1value2")?;
+    fs::write(
+        temp_dir.path().join("index.db"),
+        r#"{"key1":{"position":3,"offset":6},"key2":{"position":9,"offset":6}}"#,
+    )?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(Error::FormatErr(_))
+    ));
+
+    assert_eq!(kvs::upgrade(temp_dir.path())?, Some(2));
+    assert_eq!(kvs::upgrade(temp_dir.path())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.keys().len(), 2);
+    Ok(())
+}
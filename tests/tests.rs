@@ -1,12 +1,10 @@
 use assert_cmd::prelude::*;
 use kvs::{KvStore, Result, DeferDrop};
 use predicates::ord::eq;
-use predicates::str::{contains, is_empty, PredicateStrExt};
+use predicates::str::{contains, is_empty, is_match, PredicateStrExt};
 use std::env;
 use std::fmt::format;
-use log::{debug, error, log_enabled, info, Level};
-use env_logger::Builder;
-use std::fs::{OpenOptions, self};
+use std::fs;
 use std::path::Path;
 use std::process::Command;
 use tempfile::TempDir;
@@ -71,7 +69,7 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
@@ -100,7 +98,7 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
@@ -123,6 +121,138 @@ fn cli_rm_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs backup <DIR>` and `kvs restore <DIR>` should round-trip the store.
+#[test]
+fn cli_backup_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", backup_dir.path().to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", backup_dir.path().to_str().unwrap()])
+        .current_dir(&restore_dir)
+        .assert()
+        .success();
+
+    let store = KvStore::open(restore_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 2\n"))
+        .stdout(contains("compactions: 0\n"));
+
+    // compactions of an earlier process are still counted
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    drop(store);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 2\n"))
+        .stdout(contains("compactions: 1\n"))
+        .stdout(is_match(r"  at \d+: \d+ -> \d+ bytes in \d+ ms\n").unwrap());
+    Ok(())
+}
+
+#[test]
+fn cli_data_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let backup_dir = temp_dir.path().join("backup");
+    KvStore::open(temp_dir.path())?.backup(&backup_dir)?;
+
+    // restoring into a missing data directory creates and marks it
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--data-dir", data_dir.to_str().unwrap(), "restore", backup_dir.to_str().unwrap()])
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(data_dir.join("engine"))?, "kvs");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .env("KVS_DATA_DIR", &data_dir)
+        .args(["stats"])
+        .assert()
+        .success()
+        .stdout(contains("keys: 1\n"));
+
+    fs::write(data_dir.join("engine"), "sled")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--data-dir", data_dir.to_str().unwrap(), "stats"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn cli_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("abort.txt"), "set key1 value1\nrm key2\n")?;
+    fs::write(
+        temp_dir.path().join("batch.txt"),
+        "set key1 value1\nrm key2\nget key1\n",
+    )?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch", "--transactional", "abort.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("2: rm key2: failed"))
+        .stdout(contains("transaction aborted"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch", "--continue-on-error", "batch.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("3: get key1: value1\n"))
+        .stdout(contains("2 succeeded, 1 failed, 0 skipped\n"));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
@@ -187,7 +317,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -197,7 +327,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -208,7 +338,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -217,7 +347,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -229,14 +359,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -245,7 +375,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -253,7 +383,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -264,10 +394,8 @@ fn remove_key() -> Result<()> {
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
-    log4rs::init_file("/Users/lee/Code/RustLearn/pingcap-talent-plan/project-1/log4rs.yaml", Default::default()).unwrap();
-
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -296,7 +424,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             let db_num = store.get(key.clone())?;
@@ -322,10 +450,24 @@ fn test_defer() {
 
 
 #[test]
-fn test_log() { 
-    // 从 log4rs.yml 文件中加载配置
-    log4rs::init_file("/Users/lee/Code/RustLearn/pingcap-talent-plan/project-1/log4rs.yaml", Default::default()).unwrap();
-    // 记录日志
-    info!("这是一个信息级别的日志");
-    error!("这是一个错误级别的日志");
+fn test_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("kvs.log");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--log-level", "info", "--log-format", "json", "--log-file"])
+        .arg(&log_path)
+        .arg("stats")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(is_empty());
+
+    let log = fs::read_to_string(&log_path)?;
+    let line: serde_json::Value = serde_json::from_str(log.lines().next().unwrap())?;
+    assert_eq!(line["level"], "INFO");
+    assert_eq!(line["spans"][0]["name"], "open");
+    assert_eq!(line["fields"]["message"], "recovered the index from the log");
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,110 @@
+use kvs::{Error, NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+    const TASK_NUM: usize = 20;
+    const ADD_COUNT: usize = 1000;
+
+    let (tx, rx) = mpsc::channel();
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        let tx = tx.clone();
+        pool.spawn(move || {
+            for _ in 0..ADD_COUNT {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..TASK_NUM {
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * ADD_COUNT);
+    Ok(())
+}
+
+// Jobs spawned after a panicking job must still run on every worker
+fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 1000;
+
+    let pool = P::new(4)?;
+    for _ in 0..TASK_NUM {
+        pool.spawn(move || panic!("expected panic in a pooled job"));
+    }
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+// Dropping the pool should run every queued job before the workers exit
+#[test]
+fn shared_queue_thread_pool_shutdown() -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = SharedQueueThreadPool::new(2)?;
+    for _ in 0..100 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            std::thread::sleep(Duration::from_millis(1));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), 100);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_needs_threads() {
+    assert!(SharedQueueThreadPool::new(0).is_err());
+}
+
+// A full bounded queue turns jobs away instead of blocking the caller
+#[test]
+fn shared_queue_thread_pool_busy() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_capacity(1, 1)?;
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        release_rx.recv().unwrap();
+    });
+    started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let queued = Arc::clone(&counter);
+    pool.try_spawn(move || {
+        queued.fetch_add(1, Ordering::SeqCst);
+    })?;
+    assert!(matches!(pool.try_spawn(|| {}), Err(Error::BusyErr)));
+
+    release_tx.send(()).unwrap();
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+    Ok(())
+}